@@ -1,25 +1,161 @@
+use crate::lru::LruMap;
 use chrono::{DateTime, Utc};
 use once_cell::sync::Lazy;
-use pretty_dns_message::{qtype::QType, resource::Resource};
-use std::{collections::HashMap, sync::Mutex};
+use pretty_dns_message::{
+    header::RCode,
+    qtype::QType,
+    resource::{RData, Resource},
+};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+};
 use tracing::debug;
 
-static CACHE: Lazy<Mutex<HashMap<(String, QType), Record>>> =
-    Lazy::new(|| Mutex::new(HashMap::new()));
+static CACHE: Lazy<Mutex<Cache>> = Lazy::new(|| Mutex::new(Cache::new(DEFAULT_MAX_ENTRIES)));
+
+/// How many times an expired record was served, see `resolve_stale`.
+static STALE_SERVED: AtomicU64 = AtomicU64::new(0);
+
+pub const DEFAULT_MAX_ENTRIES: usize = 10000;
+
+/// The TTL of records in a stale answer, RFC 8767 4.
+pub const STALE_TTL: u32 = 30;
+
+type Key = (String, QType);
+
+/// Bounds for the TTLs of cached records, applied when they are stored.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TtlPolicy {
+    pub min_ttl: u32,
+    pub max_ttl: u32,
+    /// Bounds for the negative TTL of NXDOMAIN and NODATA responses.
+    pub min_negative_ttl: u32,
+    pub max_negative_ttl: u32,
+}
+
+impl Default for TtlPolicy {
+    /// No minimum, a day for records and three hours for negative
+    /// responses, the longest RFC 2308 5 suggests.
+    fn default() -> TtlPolicy {
+        TtlPolicy {
+            min_ttl: 0,
+            max_ttl: 86400,
+            min_negative_ttl: 0,
+            max_negative_ttl: 10800,
+        }
+    }
+}
+
+impl TtlPolicy {
+    pub fn clamp(&self, ttl: u32) -> u32 {
+        ttl.max(self.min_ttl).min(self.max_ttl)
+    }
+
+    pub fn clamp_negative(&self, ttl: u32) -> u32 {
+        ttl.max(self.min_negative_ttl).min(self.max_negative_ttl)
+    }
+}
+
+#[derive(Debug)]
+struct Cache {
+    records: LruMap<Key, Record>,
+    /// Seconds expired records are kept to be served stale.
+    stale_window: u32,
+    /// Records hit this many times are refreshed before they expire, see
+    /// `take_prefetch`. Zero disables prefetching.
+    prefetch_hits: u64,
+    /// The fraction of the TTL left when a record is refreshed.
+    prefetch_fraction: f64,
+    ttl_policy: TtlPolicy,
+}
+
+impl Cache {
+    fn new(max_entries: usize) -> Cache {
+        Cache {
+            records: LruMap::new(max_entries),
+            stale_window: 0,
+            prefetch_hits: 0,
+            prefetch_fraction: 0.0,
+            ttl_policy: TtlPolicy::default(),
+        }
+    }
+
+    fn get(&mut self, key: &Key) -> Option<&Record> {
+        self.records.get(key).map(|v| &*v)
+    }
+
+    fn insert(&mut self, key: Key, mut record: Record) {
+        // a refreshed record stays popular
+        if let Some(r) = self.records.peek(&key) {
+            record.hits = r.hits;
+        }
+
+        record.prefetching = false;
+        self.records.insert(key, record);
+    }
+
+    /// Count a hit on a record, and tell whether it should be refreshed now,
+    /// which is when it was hit often and little of its TTL is left. It is
+    /// told only once until the record is replaced.
+    fn take_prefetch(&mut self, key: &Key) -> bool {
+        let (prefetch_hits, prefetch_fraction) = (self.prefetch_hits, self.prefetch_fraction);
+        let r = match self.records.peek_mut(key) {
+            Some(r) => r,
+            None => return false,
+        };
+        r.hits += 1;
+        if prefetch_hits == 0 || r.hits < prefetch_hits || r.prefetching || r.expired() {
+            return false;
+        }
+
+        let left = r.ttl - r.age();
+        if f64::from(left) > f64::from(r.ttl) * prefetch_fraction {
+            return false;
+        }
+
+        r.prefetching = true;
+        true
+    }
+
+    fn remove(&mut self, key: &Key) {
+        self.records.remove(key);
+    }
+
+    /// Remove the records which expired longer than the stale window ago,
+    /// and return how many were removed.
+    fn sweep(&mut self) -> usize {
+        let stale_window = self.stale_window;
+        self.records
+            .retain(|v| v.age() < v.ttl.saturating_add(stale_window))
+    }
+}
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Record {
     cached_at: DateTime<Utc>,
+    /// Seconds the record can be used for, which is the smallest TTL in the
+    /// answer, or the negative TTL for a response without an answer.
+    ttl: u32,
+    /// How many times the record was answered, see `take_prefetch`.
+    hits: u64,
+    /// Whether the record is being refreshed, see `Cache::take_prefetch`.
+    prefetching: bool,
     pub data: CacheData,
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct CacheData {
+    /// NOERROR, or NXDOMAIN for a name which does not exist.
+    pub rcode: RCode,
     pub answer: Vec<Resource>,
     pub authority: Vec<Resource>,
     pub additional: Vec<Resource>,
 }
 
+/// Look up a whole response. Positive answers are built from the RRsets
+/// first, see `rrset::resolve`, so this mostly answers negative responses
+/// and keeps the responses which are served stale.
 pub fn resolve(domain: String, qtype: QType) -> Option<CacheData> {
     debug!("try to resolve cache: {:?} {:?}", domain, qtype);
     let mut c = CACHE.lock().unwrap();
@@ -27,76 +163,239 @@ pub fn resolve(domain: String, qtype: QType) -> Option<CacheData> {
 
     if r.expired() {
         debug!("cache is expired: {:?} {:?}", domain, qtype);
-        c.remove(&(domain, qtype));
+        if r.age() >= r.ttl.saturating_add(c.stale_window) {
+            c.remove(&(domain, qtype));
+        }
         return None;
     }
 
     debug!("found cache: {:?} {:?}", domain, qtype);
-
     r.update_ttl();
 
-    return Some(r.data);
+    Some(r.data)
 }
 
-impl Record {
-    fn expired(&self) -> bool {
-        let now = Utc::now();
-        let diff = (now - self.cached_at).num_seconds() as u32;
+/// Look up a record which may have expired within the stale window, for
+/// when it cannot be resolved again (RFC 8767). The records are given
+/// `STALE_TTL`, so that clients ask again soon.
+pub fn resolve_stale(domain: String, qtype: QType) -> Option<CacheData> {
+    let mut c = CACHE.lock().unwrap();
+    let stale_window = c.stale_window;
+    let mut r = c.get(&(domain.clone(), qtype))?.clone();
 
-        return self.data.answer.iter().any(|v| v.ttl <= diff);
+    if !r.expired() {
+        r.update_ttl();
+        return Some(r.data);
+    }
+    if r.age() >= r.ttl.saturating_add(stale_window) {
+        return None;
     }
 
-    fn update_ttl(&mut self) {
-        let now = Utc::now();
-        let diff = (now - self.cached_at).num_seconds() as u32;
+    let served = STALE_SERVED.fetch_add(1, Ordering::Relaxed) + 1;
+    debug!(
+        "serve stale cache: {:?} {:?}, {} times in total",
+        domain, qtype, served
+    );
 
-        for i in 0..self.data.answer.len() {
-            self.data.answer[i].ttl = self.data.answer[i].ttl - diff;
-        }
+    for v in r
+        .data
+        .answer
+        .iter_mut()
+        .chain(r.data.authority.iter_mut())
+        .chain(r.data.additional.iter_mut())
+    {
+        v.ttl = STALE_TTL;
+    }
 
-        for i in 0..self.data.authority.len() {
-            self.data.authority[i].ttl = self.data.authority[i].ttl - diff;
-        }
+    Some(r.data)
+}
+
+/// How many times an expired record was served since the start.
+pub fn stale_served() -> u64 {
+    STALE_SERVED.load(Ordering::Relaxed)
+}
+
+impl Record {
+    /// Seconds since the record was cached.
+    fn age(&self) -> u32 {
+        (Utc::now() - self.cached_at).num_seconds() as u32
+    }
 
-        for i in 0..self.data.additional.len() {
-            self.data.additional[i].ttl = self.data.additional[i].ttl - diff;
+    fn expired(&self) -> bool {
+        self.age() >= self.ttl
+    }
+
+    /// Decrease the TTLs by the time the record was cached for. Records in
+    /// the authority and additional sections may have shorter TTLs than the
+    /// answer, so they stop at zero.
+    fn update_ttl(&mut self) {
+        let diff = self.age();
+
+        for v in self
+            .data
+            .answer
+            .iter_mut()
+            .chain(self.data.authority.iter_mut())
+            .chain(self.data.additional.iter_mut())
+        {
+            v.ttl = v.ttl.saturating_sub(diff);
         }
     }
 }
 
+/// Store a response. NXDOMAIN and NODATA responses are cached for the
+/// negative TTL from the SOA record in the authority section (RFC 2308 5),
+/// and are not cached without one. Other errors are never cached. The TTLs
+/// are clamped by the `TtlPolicy`.
 pub fn cache(
     domain: String,
     qtype: QType,
-    answer: &Vec<Resource>,
-    authority: &Vec<Resource>,
-    additional: &Vec<Resource>,
-) -> Result<(), ()> {
-    debug!("store cache: {:?} {:?}", domain, qtype);
+    rcode: RCode,
+    answer: &[Resource],
+    authority: &[Resource],
+    additional: &[Resource],
+) {
+    let ttl = match rcode {
+        RCode::NoError if !answer.is_empty() => answer.iter().map(|v| v.ttl).min(),
+        RCode::NoError | RCode::NXDomain => negative_ttl(authority),
+        _ => None,
+    };
+    let ttl = match ttl {
+        Some(ttl) => ttl,
+        None => {
+            debug!("not cacheable: {:?} {:?} {:?}", domain, qtype, rcode);
+            return;
+        }
+    };
 
     let mut c = CACHE.lock().unwrap();
+    let policy = c.ttl_policy;
+
+    let mut answer = answer.to_vec();
+    let mut authority = authority.to_vec();
+    let mut additional = additional.to_vec();
+    let ttl = if answer.is_empty() {
+        // the SOA record is given to clients with the negative TTL
+        let ttl = policy.clamp_negative(ttl);
+        for v in authority.iter_mut() {
+            v.ttl = policy.clamp_negative(v.ttl).min(ttl);
+        }
+
+        ttl
+    } else {
+        for v in answer
+            .iter_mut()
+            .chain(authority.iter_mut())
+            .chain(additional.iter_mut())
+        {
+            v.ttl = policy.clamp(v.ttl);
+        }
+
+        policy.clamp(ttl)
+    };
+
+    debug!("store cache: {:?} {:?} {:?} {}", domain, qtype, rcode, ttl);
     c.insert(
         (domain, qtype),
         Record {
             cached_at: Utc::now(),
+            ttl,
+            hits: 0,
+            prefetching: false,
             data: CacheData {
-                answer: answer.clone(),
-                authority: authority.clone(),
-                additional: additional.clone(),
+                rcode,
+                answer,
+                authority,
+                additional,
             },
         },
     );
-    return Ok(());
+}
+
+/// Limit the number of cached records. Records are evicted when there are
+/// already more.
+pub fn set_max_entries(max_entries: usize) {
+    CACHE.lock().unwrap().records.set_max_entries(max_entries);
+}
+
+/// Refresh records hit at least `hits` times when `fraction` of their TTL is
+/// left, see `take_prefetch`. Zero hits disables prefetching.
+pub fn set_prefetch(hits: u64, fraction: f64) {
+    let mut c = CACHE.lock().unwrap();
+    c.prefetch_hits = hits;
+    c.prefetch_fraction = fraction;
+}
+
+/// Count a hit on a cached answer, and tell whether it is popular and about
+/// to expire, so that it should be resolved again in the background. This
+/// returns true once for each record, so that only one refresh runs at a
+/// time.
+pub fn take_prefetch(domain: String, qtype: QType) -> bool {
+    CACHE.lock().unwrap().take_prefetch(&(domain, qtype))
+}
+
+pub fn set_ttl_policy(policy: TtlPolicy) {
+    CACHE.lock().unwrap().ttl_policy = policy;
+}
+
+pub fn ttl_policy() -> TtlPolicy {
+    CACHE.lock().unwrap().ttl_policy
+}
+
+/// Keep expired records for `seconds` to serve them stale, see `resolve_stale`.
+pub fn set_stale_window(seconds: u32) {
+    CACHE.lock().unwrap().stale_window = seconds;
+}
+
+/// Remove the expired records, which otherwise stay until they are looked up.
+pub fn sweep() -> usize {
+    let mut c = CACHE.lock().unwrap();
+    let removed = c.sweep();
+    debug!("sweep cache: {} records, {} left", removed, c.records.len());
+
+    removed
+}
+
+/// The TTL of a negative response is the smaller of the TTL of the SOA
+/// record and its MINIMUM field, RFC 2308 5.
+fn negative_ttl(authority: &[Resource]) -> Option<u32> {
+    authority.iter().find_map(|v| match v.rdata {
+        RData::SOA { minimum, .. } => Some(v.ttl.min(minimum)),
+        _ => None,
+    })
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{cache, resolve};
+    use super::{
+        cache, negative_ttl, resolve, resolve_stale, set_stale_window, stale_served, Cache, Record,
+        TtlPolicy, STALE_TTL,
+    };
     use pretty_dns_message::{
-        compression::{CompressionData, CompressionType, DataType},
         domain::Domain,
+        header::RCode,
         qtype::QType,
-        resource::Resource,
+        resource::{RData, Resource},
     };
+    use std::net::Ipv4Addr;
+
+    fn soa(ttl: u32, minimum: u32) -> Resource {
+        Resource {
+            name: Domain::from("example.com."),
+            _type: QType::SOA,
+            class: 1,
+            ttl,
+            rdata: RData::SOA {
+                mname: Domain::from("ns.example.com."),
+                rname: Domain::from("hostmaster.example.com."),
+                serial: 1,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum,
+            },
+        }
+    }
 
     #[test]
     fn test_resolve_none() {
@@ -109,25 +408,226 @@ mod tests {
     fn test_resolve_some() {
         let domain = "test.example.com.".to_owned();
         let resource = Resource {
-            name: CompressionData::new(
-                vec![
-                    DataType::Raw(vec![103, 111, 111, 103, 108, 101]),
-                    DataType::Raw(vec![99, 111, 109]),
-                ],
-                CompressionType::Domain,
-            ),
+            name: Domain::from("test.example.com."),
             _type: QType::A,
             class: 1,
             ttl: 299,
-            rdlength: 4,
-            rdata: CompressionData::new(
-                vec![DataType::Raw(vec![172, 217, 25, 238])],
-                CompressionType::Data,
-            ),
+            rdata: RData::A(Ipv4Addr::new(172, 217, 25, 238)),
         };
-        cache(domain.clone(), QType::A, &vec![resource], &vec![], &vec![]).unwrap();
+        cache(
+            domain.clone(),
+            QType::A,
+            RCode::NoError,
+            &[resource],
+            &[],
+            &[],
+        );
 
         let list = resolve(domain, QType::A);
         assert!(list.is_some());
     }
+
+    #[test]
+    fn test_resolve_negative() {
+        let domain = "nxdomain.example.com.".to_owned();
+        cache(
+            domain.clone(),
+            QType::A,
+            RCode::NXDomain,
+            &[],
+            &[soa(3600, 300)],
+            &[],
+        );
+
+        let data = resolve(domain, QType::A).unwrap();
+        assert_eq!(data.rcode, RCode::NXDomain);
+        assert_eq!(data.answer, vec![]);
+        assert_eq!(data.authority[0].ttl, 300);
+
+        // NODATA
+        let domain = "nodata.example.com.".to_owned();
+        cache(
+            domain.clone(),
+            QType::AAAA,
+            RCode::NoError,
+            &[],
+            &[soa(3600, 300)],
+            &[],
+        );
+        assert_eq!(
+            resolve(domain.clone(), QType::AAAA).unwrap().rcode,
+            RCode::NoError
+        );
+
+        // not cached without a SOA record, or with a TTL of zero
+        cache(domain.clone(), QType::TXT, RCode::NoError, &[], &[], &[]);
+        assert_eq!(resolve(domain.clone(), QType::TXT), None);
+        cache(
+            domain.clone(),
+            QType::MX,
+            RCode::NXDomain,
+            &[],
+            &[soa(0, 300)],
+            &[],
+        );
+        assert_eq!(resolve(domain.clone(), QType::MX), None);
+
+        cache(
+            domain.clone(),
+            QType::SRV,
+            RCode::ServFail,
+            &[],
+            &[soa(3600, 300)],
+            &[],
+        );
+        assert_eq!(resolve(domain, QType::SRV), None);
+    }
+
+    fn record(ttl: u32) -> Record {
+        Record {
+            cached_at: chrono::Utc::now(),
+            ttl,
+            hits: 0,
+            prefetching: false,
+            data: super::CacheData {
+                rcode: RCode::NoError,
+                answer: vec![],
+                authority: vec![],
+                additional: vec![],
+            },
+        }
+    }
+
+    #[test]
+    fn test_sweep() {
+        let mut c = Cache::new(10);
+        c.insert(("a.".to_owned(), QType::A), record(300));
+        c.insert(("b.".to_owned(), QType::A), record(0));
+
+        c.stale_window = 3600;
+        assert_eq!(c.sweep(), 0);
+
+        c.stale_window = 0;
+        assert_eq!(c.sweep(), 1);
+        assert_eq!(c.records.len(), 1);
+    }
+
+    #[test]
+    fn test_take_prefetch() {
+        let key = ("a.".to_owned(), QType::A);
+
+        let mut c = Cache::new(10);
+        c.prefetch_hits = 2;
+        c.prefetch_fraction = 1.0;
+        c.insert(key.clone(), record(300));
+        assert!(!c.take_prefetch(&key));
+
+        c.records.peek_mut(&key).unwrap().hits = 2;
+        assert!(c.take_prefetch(&key));
+        assert!(!c.take_prefetch(&key));
+
+        // the refreshed record keeps its hits
+        c.insert(key.clone(), record(300));
+        assert!(c.take_prefetch(&key));
+
+        // too much of the TTL is left
+        c.prefetch_fraction = 0.1;
+        c.insert(key.clone(), record(300));
+        assert!(!c.take_prefetch(&key));
+
+        c.prefetch_hits = 0;
+        c.prefetch_fraction = 1.0;
+        assert!(!c.take_prefetch(&key));
+    }
+
+    #[test]
+    fn test_resolve_stale() {
+        set_stale_window(3600);
+
+        let domain = "stale.example.com.".to_owned();
+        let resource = Resource {
+            name: Domain::from("stale.example.com."),
+            _type: QType::A,
+            class: 1,
+            ttl: 0,
+            rdata: RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        };
+        cache(
+            domain.clone(),
+            QType::A,
+            RCode::NoError,
+            &[resource],
+            &[],
+            &[],
+        );
+
+        // expired records are only served when asked for stale data
+        assert_eq!(resolve(domain.clone(), QType::A), None);
+        let served = stale_served();
+        let data = resolve_stale(domain, QType::A).unwrap();
+        assert_eq!(data.answer[0].ttl, STALE_TTL);
+        assert!(stale_served() > served);
+
+        assert_eq!(
+            resolve_stale("none.example.com.".to_owned(), QType::A),
+            None
+        );
+    }
+
+    #[test]
+    fn test_ttl_policy() {
+        let policy = TtlPolicy {
+            min_ttl: 60,
+            max_ttl: 3600,
+            min_negative_ttl: 30,
+            max_negative_ttl: 300,
+        };
+        assert_eq!(policy.clamp(0), 60);
+        assert_eq!(policy.clamp(300), 300);
+        assert_eq!(policy.clamp(604800), 3600);
+        assert_eq!(policy.clamp_negative(0), 30);
+        assert_eq!(policy.clamp_negative(3600), 300);
+
+        let domain = "max-ttl.example.com.".to_owned();
+        let resource = Resource {
+            name: Domain::from("max-ttl.example.com."),
+            _type: QType::A,
+            class: 1,
+            ttl: 604800,
+            rdata: RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        };
+        cache(
+            domain.clone(),
+            QType::A,
+            RCode::NoError,
+            &[resource],
+            &[],
+            &[],
+        );
+        let data = resolve(domain, QType::A).unwrap();
+        assert_eq!(data.answer[0].ttl, TtlPolicy::default().max_ttl);
+    }
+
+    #[test]
+    fn test_update_ttl() {
+        let mut r = record(300);
+        r.cached_at = chrono::Utc::now() - chrono::Duration::seconds(60);
+        r.data.additional = vec![Resource {
+            name: Domain::from("ns.example.com."),
+            _type: QType::A,
+            class: 1,
+            ttl: 10,
+            rdata: RData::A(Ipv4Addr::new(192, 0, 2, 53)),
+        }];
+
+        r.update_ttl();
+        assert_eq!(r.data.additional[0].ttl, 0);
+    }
+
+    #[test]
+    fn test_negative_ttl() {
+        assert_eq!(negative_ttl(&[soa(3600, 300)]), Some(300));
+        assert_eq!(negative_ttl(&[soa(60, 300)]), Some(60));
+        assert_eq!(negative_ttl(&[]), None);
+    }
 }
@@ -0,0 +1,129 @@
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+/// What is known about each nameserver, like the infrastructure cache of
+/// BIND and Unbound.
+static INFRA: Lazy<Mutex<HashMap<IpAddr, Server>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The smoothed RTT is capped so that a server which timed out is tried
+/// again at some point.
+const MAX_SRTT: Duration = Duration::from_secs(10);
+
+/// How long a server stays lame for a zone.
+const LAME_TTL: Duration = Duration::from_secs(600);
+
+#[derive(Clone, Debug, Default)]
+struct Server {
+    srtt: Duration,
+    /// Zones the server does not answer for, and when that expires.
+    lame: HashMap<String, Instant>,
+}
+
+/// Sort the servers by smoothed RTT, the fastest first. Servers which have
+/// not been queried yet come first so that they get measured, and servers
+/// which are lame for `zone` come last.
+pub fn sort(zone: &str, servers: &mut [SocketAddr]) {
+    let infra = INFRA.lock().unwrap();
+    let now = Instant::now();
+
+    servers.sort_by_key(|v| match infra.get(&v.ip()) {
+        Some(s) => (s.lame.get(zone).map_or(false, |v| *v > now), s.srtt),
+        None => (false, Duration::ZERO),
+    });
+}
+
+/// Smooth the RTT of a server with a new sample, with the same weight as
+/// BIND.
+pub fn update_rtt(server: IpAddr, rtt: Duration) {
+    let mut infra = INFRA.lock().unwrap();
+    let s = infra.entry(server).or_default();
+
+    s.srtt = if s.srtt.is_zero() {
+        rtt
+    } else {
+        (s.srtt * 7 + rtt * 3) / 10
+    }
+    .min(MAX_SRTT);
+    debug!("update rtt: {:?} {:?}", server, s.srtt);
+}
+
+/// Penalize a server which did not answer, as if it took `timeout`.
+pub fn failed(server: IpAddr, timeout: Duration) {
+    let mut infra = INFRA.lock().unwrap();
+    let s = infra.entry(server).or_default();
+
+    s.srtt = (s.srtt.max(timeout) * 2).min(MAX_SRTT);
+    debug!("server failed: {:?} {:?}", server, s.srtt);
+}
+
+pub fn mark_lame(server: IpAddr, zone: &str) {
+    debug!("mark lame: {:?} {:?}", server, zone);
+
+    let mut infra = INFRA.lock().unwrap();
+    infra
+        .entry(server)
+        .or_default()
+        .lame
+        .insert(zone.to_owned(), Instant::now() + LAME_TTL);
+}
+
+pub fn is_lame(server: IpAddr, zone: &str) -> bool {
+    let infra = INFRA.lock().unwrap();
+    infra
+        .get(&server)
+        .and_then(|s| s.lame.get(zone))
+        .map_or(false, |v| *v > Instant::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{failed, is_lame, mark_lame, sort, update_rtt};
+    use std::{
+        net::{IpAddr, SocketAddr},
+        time::Duration,
+    };
+
+    #[test]
+    fn test_sort() {
+        let fast: IpAddr = "192.0.2.1".parse().unwrap();
+        let slow: IpAddr = "192.0.2.2".parse().unwrap();
+        let down: IpAddr = "192.0.2.3".parse().unwrap();
+        let lame: IpAddr = "192.0.2.4".parse().unwrap();
+        let new: IpAddr = "192.0.2.5".parse().unwrap();
+
+        update_rtt(fast, Duration::from_millis(10));
+        update_rtt(slow, Duration::from_millis(100));
+        failed(down, Duration::from_secs(3));
+        update_rtt(lame, Duration::from_millis(1));
+        mark_lame(lame, "example.com.");
+
+        let mut servers: Vec<SocketAddr> = vec![lame, down, slow, fast, new]
+            .into_iter()
+            .map(|v| SocketAddr::new(v, 53))
+            .collect();
+        sort("example.com.", &mut servers);
+        assert_eq!(
+            servers.iter().map(|v| v.ip()).collect::<Vec<_>>(),
+            vec![new, fast, slow, down, lame]
+        );
+
+        assert!(is_lame(lame, "example.com."));
+        assert!(!is_lame(lame, "example.net."));
+    }
+
+    #[test]
+    fn test_update_rtt() {
+        let server: IpAddr = "192.0.2.10".parse().unwrap();
+        update_rtt(server, Duration::from_millis(100));
+        update_rtt(server, Duration::from_millis(200));
+
+        let infra = super::INFRA.lock().unwrap();
+        assert_eq!(infra[&server].srtt, Duration::from_millis(130));
+    }
+}
@@ -1 +1,4 @@
 pub mod cache;
+pub mod infra;
+mod lru;
+pub mod rrset;
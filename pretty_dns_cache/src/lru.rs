@@ -0,0 +1,167 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    hash::Hash,
+};
+use tracing::debug;
+
+/// A map with a limit on its number of entries, which evicts the least
+/// recently used entry to make room for a new one.
+#[derive(Debug)]
+pub struct LruMap<K, V> {
+    /// Values with the tick of the map when they were used last.
+    entries: HashMap<K, (u64, V)>,
+    /// Keys by the time they were used last, the oldest first.
+    lru: BTreeMap<u64, K>,
+    tick: u64,
+    max_entries: usize,
+}
+
+impl<K: Hash + Eq + Clone + Debug, V> LruMap<K, V> {
+    pub fn new(max_entries: usize) -> LruMap<K, V> {
+        LruMap {
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            max_entries,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Look up an entry and mark it as used.
+    pub fn get(&mut self, key: &K) -> Option<&mut V> {
+        self.touch(key);
+        self.peek_mut(key)
+    }
+
+    /// Look up an entry without changing when it is evicted.
+    pub fn peek(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).map(|(_, v)| v)
+    }
+
+    pub fn peek_mut(&mut self, key: &K) -> Option<&mut V> {
+        self.entries.get_mut(key).map(|(_, v)| v)
+    }
+
+    /// Mark an entry as used, so that it is evicted later.
+    pub fn touch(&mut self, key: &K) {
+        self.tick += 1;
+
+        if let Some((used, _)) = self.entries.get_mut(key) {
+            self.lru.remove(used);
+            self.lru.insert(self.tick, key.clone());
+            *used = self.tick;
+        }
+    }
+
+    /// Store an entry, replacing the one for the same key. The oldest
+    /// entries are evicted when the map is full.
+    pub fn insert(&mut self, key: K, value: V) {
+        self.tick += 1;
+        self.remove(&key);
+
+        while self.entries.len() >= self.max_entries {
+            let (_, oldest) = match self.lru.pop_first() {
+                Some(v) => v,
+                None => break,
+            };
+            debug!("evict cache: {:?}", oldest);
+            self.entries.remove(&oldest);
+        }
+        if self.max_entries == 0 {
+            return;
+        }
+
+        self.lru.insert(self.tick, key.clone());
+        self.entries.insert(key, (self.tick, value));
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let (used, value) = self.entries.remove(key)?;
+        self.lru.remove(&used);
+
+        Some(value)
+    }
+
+    /// Limit the number of entries, evicting the oldest when there are
+    /// already more.
+    pub fn set_max_entries(&mut self, max_entries: usize) {
+        self.max_entries = max_entries;
+        while self.entries.len() > self.max_entries {
+            if let Some((_, oldest)) = self.lru.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+
+    /// Remove the entries for which `keep` is false, and return how many
+    /// were removed.
+    pub fn retain(&mut self, keep: impl Fn(&V) -> bool) -> usize {
+        let removed: Vec<K> = self
+            .entries
+            .iter()
+            .filter(|(_, (_, v))| !keep(v))
+            .map(|(k, _)| k.clone())
+            .collect();
+
+        for key in &removed {
+            self.remove(key);
+        }
+
+        removed.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LruMap;
+
+    #[test]
+    fn test_lru() {
+        let mut c = LruMap::new(2);
+        c.insert("a.", 1);
+        c.insert("b.", 2);
+        assert!(c.get(&"a.").is_some());
+
+        c.insert("c.", 3);
+        assert_eq!(c.peek(&"a."), Some(&1));
+        assert_eq!(c.peek(&"b."), None);
+        assert_eq!(c.peek(&"c."), Some(&3));
+
+        // replacing an entry does not evict another one
+        c.insert("c.", 4);
+        assert_eq!(c.len(), 2);
+        assert_eq!(c.lru.len(), 2);
+        assert_eq!(c.peek(&"a."), Some(&1));
+
+        // looking at an entry does not keep it
+        c.peek(&"a.");
+        c.set_max_entries(1);
+        assert_eq!(c.len(), 1);
+        assert_eq!(c.peek(&"c."), Some(&4));
+    }
+
+    #[test]
+    fn test_retain() {
+        let mut c = LruMap::new(10);
+        c.insert("a.", 1);
+        c.insert("b.", 2);
+        c.insert("c.", 3);
+
+        assert_eq!(c.retain(|v| *v != 2), 1);
+        assert_eq!(c.len(), 2);
+        assert_eq!(c.lru.len(), 2);
+        assert_eq!(c.peek(&"b."), None);
+    }
+
+    #[test]
+    fn test_zero_entries() {
+        let mut c = LruMap::new(0);
+        c.insert("a.", 1);
+        assert_eq!(c.len(), 0);
+        assert_eq!(c.lru.len(), 0);
+    }
+}
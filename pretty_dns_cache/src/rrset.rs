@@ -0,0 +1,369 @@
+use crate::lru::LruMap;
+use once_cell::sync::Lazy;
+use pretty_dns_message::{
+    domain::Domain,
+    qtype::QType,
+    resource::{RData, Resource},
+};
+use std::{
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+/// The records seen by the resolver, kept by RRset so that referrals and
+/// glue can be used for other queries.
+static RRSETS: Lazy<Mutex<RRsets>> =
+    Lazy::new(|| Mutex::new(RRsets::new(crate::cache::DEFAULT_MAX_ENTRIES)));
+
+/// Owner name in lowercase, type and class.
+type Key = (String, QType, u16);
+
+#[derive(Debug)]
+struct RRsets {
+    rrsets: LruMap<Key, RRset>,
+}
+
+/// How much an RRset can be trusted by where it was found, the least
+/// trustworthy first, RFC 2181 5.4.1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Credibility {
+    /// The additional section, or the authority section of a
+    /// non-authoritative answer like the NS records of a referral.
+    Additional,
+    /// The answer section of a non-authoritative answer.
+    NonAuthAnswer,
+    /// The authority section of an authoritative answer.
+    AuthAuthority,
+    /// The answer section of an authoritative answer.
+    AuthAnswer,
+}
+
+#[derive(Clone, Debug)]
+struct RRset {
+    cached_at: Instant,
+    /// The smallest TTL of the records.
+    ttl: u32,
+    credibility: Credibility,
+    records: Vec<Resource>,
+}
+
+impl RRset {
+    fn expired(&self) -> bool {
+        self.cached_at.elapsed() >= Duration::from_secs(self.ttl.into())
+    }
+}
+
+impl RRsets {
+    fn new(max_entries: usize) -> RRsets {
+        RRsets {
+            rrsets: LruMap::new(max_entries),
+        }
+    }
+
+    /// Store an RRset unless a more credible one is cached for the same key.
+    fn insert(&mut self, key: Key, rrset: RRset) {
+        if let Some(v) = self.rrsets.peek(&key) {
+            if !v.expired() && v.credibility > rrset.credibility {
+                debug!(
+                    "keep more credible rrset: {:?} {:?} > {:?}",
+                    key, v.credibility, rrset.credibility
+                );
+                return;
+            }
+        }
+
+        self.rrsets.insert(key, rrset);
+    }
+
+    fn get(&self, key: &Key) -> Option<&RRset> {
+        self.rrsets.peek(key).filter(|v| !v.expired())
+    }
+
+    /// Addresses of a name from the cached A and AAAA records.
+    fn addresses(&self, name: &Domain) -> Vec<IpAddr> {
+        [QType::A, QType::AAAA]
+            .iter()
+            .filter_map(|qtype| self.get(&key(name, *qtype, 1)))
+            .flat_map(|v| v.records.iter())
+            .filter_map(|r| match r.rdata {
+                RData::A(addr) => Some(IpAddr::V4(addr)),
+                RData::AAAA(addr) => Some(IpAddr::V6(addr)),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+fn key(name: &Domain, qtype: QType, class: u16) -> Key {
+    let name = Domain::from_labels(&name.labels()).to_string();
+
+    (name.to_ascii_lowercase(), qtype, class)
+}
+
+/// Store the RRsets of a response, ranked by the section they are in and
+/// whether the response is authoritative. The TTLs are clamped by the
+/// `TtlPolicy` of the cache.
+pub fn cache(aa: bool, answer: &[Resource], authority: &[Resource], additional: &[Resource]) {
+    let policy = crate::cache::ttl_policy();
+    let (answer_credibility, authority_credibility) = if aa {
+        (Credibility::AuthAnswer, Credibility::AuthAuthority)
+    } else {
+        (Credibility::NonAuthAnswer, Credibility::Additional)
+    };
+
+    let mut c = RRSETS.lock().unwrap();
+    for (records, credibility) in [
+        (answer, answer_credibility),
+        (authority, authority_credibility),
+        (additional, Credibility::Additional),
+    ] {
+        for (key, mut records) in group(records) {
+            for v in records.iter_mut() {
+                v.ttl = policy.clamp(v.ttl);
+            }
+
+            let ttl = records.iter().map(|v| v.ttl).min().unwrap_or_default();
+            c.insert(
+                key,
+                RRset {
+                    cached_at: Instant::now(),
+                    ttl,
+                    credibility,
+                    records,
+                },
+            );
+        }
+    }
+}
+
+/// Split records into RRsets, keeping their order.
+fn group(records: &[Resource]) -> Vec<(Key, Vec<Resource>)> {
+    let mut rrsets: Vec<(Key, Vec<Resource>)> = vec![];
+    for r in records {
+        // the OPT pseudo record is not data
+        if r._type == QType::OPT {
+            continue;
+        }
+
+        let k = key(&r.name, r._type, r.class);
+        match rrsets.iter_mut().find(|(v, _)| *v == k) {
+            Some((_, v)) => v.push(r.clone()),
+            None => rrsets.push((k, vec![r.clone()])),
+        }
+    }
+
+    rrsets
+}
+
+/// Look up an RRset, with the TTLs of the records decreased by the time
+/// they were cached for.
+pub fn resolve(name: &Domain, qtype: QType, class: u16) -> Option<(Vec<Resource>, Credibility)> {
+    let mut c = RRSETS.lock().unwrap();
+    let k = key(name, qtype, class);
+    c.rrsets.touch(&k);
+    let rrset = c.get(&k)?;
+
+    let elapsed = rrset.cached_at.elapsed().as_secs() as u32;
+    let records = rrset
+        .records
+        .iter()
+        .map(|v| Resource {
+            ttl: v.ttl.saturating_sub(elapsed),
+            ..v.clone()
+        })
+        .collect();
+
+    Some((records, rrset.credibility))
+}
+
+/// Addresses of a name from the cached A and AAAA records, including glue.
+pub fn addresses(name: &Domain) -> Vec<IpAddr> {
+    RRSETS.lock().unwrap().addresses(name)
+}
+
+/// Find the closest zone above or at `qname` with cached NS records and
+/// addresses for at least one of its nameservers, to start resolving from.
+/// The root is not returned, since it is always known from the hints.
+pub fn delegation(qname: &Domain) -> Option<(Domain, Vec<IpAddr>)> {
+    let mut c = RRSETS.lock().unwrap();
+    let labels = qname.labels();
+
+    for i in 0..labels.len() {
+        let zone = Domain::from_labels(&labels[i..]);
+        let k = key(&zone, QType::NS, 1);
+        let ns = match c.get(&k) {
+            Some(v) => v,
+            None => continue,
+        };
+
+        let addrs: Vec<IpAddr> = ns
+            .records
+            .iter()
+            .filter_map(|r| match r.rdata {
+                RData::NS(ref name) => Some(c.addresses(name)),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        if !addrs.is_empty() {
+            debug!("found cached delegation: {:?}", zone.to_string());
+            c.rrsets.touch(&k);
+            return Some((zone, addrs));
+        }
+    }
+
+    None
+}
+
+pub fn set_max_entries(max_entries: usize) {
+    RRSETS.lock().unwrap().rrsets.set_max_entries(max_entries);
+}
+
+/// Remove the expired RRsets, and return how many were removed.
+pub fn sweep() -> usize {
+    let mut c = RRSETS.lock().unwrap();
+    let removed = c.rrsets.retain(|v| !v.expired());
+    debug!(
+        "sweep rrset cache: {} rrsets, {} left",
+        removed,
+        c.rrsets.len()
+    );
+
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cache, delegation, group, key, resolve, Credibility, RRset, RRsets};
+    use pretty_dns_message::{
+        domain::Domain,
+        qtype::QType,
+        resource::{RData, Resource},
+    };
+    use std::{net::Ipv4Addr, time::Instant};
+
+    fn record(name: &str, ttl: u32, rdata: RData) -> Resource {
+        let _type = match rdata {
+            RData::A(_) => QType::A,
+            RData::NS(_) => QType::NS,
+            _ => QType::TXT,
+        };
+
+        Resource {
+            name: Domain::from(name),
+            _type,
+            class: 1,
+            ttl,
+            rdata,
+        }
+    }
+
+    fn rrset(credibility: Credibility) -> RRset {
+        RRset {
+            cached_at: Instant::now(),
+            ttl: 300,
+            credibility,
+            records: vec![],
+        }
+    }
+
+    #[test]
+    fn test_group() {
+        let records = vec![
+            record("a.example.", 300, RData::A(Ipv4Addr::new(192, 0, 2, 1))),
+            record("b.example.", 300, RData::A(Ipv4Addr::new(192, 0, 2, 2))),
+            record("A.example.", 60, RData::A(Ipv4Addr::new(192, 0, 2, 3))),
+        ];
+
+        let rrsets = group(&records);
+        assert_eq!(rrsets.len(), 2);
+        assert_eq!(rrsets[0].0, ("a.example.".to_owned(), QType::A, 1));
+        assert_eq!(rrsets[0].1.len(), 2);
+    }
+
+    #[test]
+    fn test_credibility() {
+        let k = key(&Domain::from("example."), QType::NS, 1);
+        let mut c = RRsets::new(1);
+
+        c.insert(k.clone(), rrset(Credibility::AuthAnswer));
+        c.insert(k.clone(), rrset(Credibility::Additional));
+        assert_eq!(c.get(&k).unwrap().credibility, Credibility::AuthAnswer);
+
+        // an expired RRset is replaced by anything
+        c.rrsets.peek_mut(&k).unwrap().ttl = 0;
+        c.insert(k.clone(), rrset(Credibility::Additional));
+        assert_eq!(c.get(&k).unwrap().credibility, Credibility::Additional);
+
+        // a full cache makes room for a new RRset
+        let a = key(&Domain::from("example."), QType::A, 1);
+        c.insert(a.clone(), rrset(Credibility::Additional));
+        assert_eq!(c.rrsets.len(), 1);
+        assert!(c.get(&a).is_some());
+    }
+
+    #[test]
+    fn test_additional_does_not_overwrite_answer() {
+        let name = Domain::from("www.answer.example.");
+        cache(
+            false,
+            &[record(
+                "www.answer.example.",
+                300,
+                RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            )],
+            &[],
+            &[record(
+                "www.answer.example.",
+                300,
+                RData::A(Ipv4Addr::new(203, 0, 113, 1)),
+            )],
+        );
+        cache(
+            true,
+            &[],
+            &[],
+            &[record(
+                "www.answer.example.",
+                300,
+                RData::A(Ipv4Addr::new(203, 0, 113, 2)),
+            )],
+        );
+
+        let (records, credibility) = resolve(&name, QType::A, 1).unwrap();
+        assert_eq!(records[0].rdata, RData::A(Ipv4Addr::new(192, 0, 2, 1)));
+        assert_eq!(credibility, Credibility::NonAuthAnswer);
+    }
+
+    #[test]
+    fn test_delegation() {
+        // a referral to rrset.example. with glue
+        cache(
+            false,
+            &[],
+            &[record(
+                "rrset.example.",
+                300,
+                RData::NS(Domain::from("ns.rrset.example.")),
+            )],
+            &[record(
+                "ns.rrset.example.",
+                300,
+                RData::A(Ipv4Addr::new(192, 0, 2, 53)),
+            )],
+        );
+
+        let (zone, addrs) = delegation(&Domain::from("www.sub.RRSET.example.")).unwrap();
+        assert!(zone.eq_ignore_case(&Domain::from("rrset.example.")));
+        assert_eq!(addrs, vec![Ipv4Addr::new(192, 0, 2, 53)]);
+        assert_eq!(delegation(&Domain::from("other.example.")), None);
+
+        let (records, credibility) =
+            resolve(&Domain::from("rrset.example."), QType::NS, 1).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(credibility, Credibility::Additional);
+        assert_eq!(resolve(&Domain::from("rrset.example."), QType::NS, 3), None);
+    }
+}
@@ -1,15 +1,42 @@
-use pretty_dns_message::{header::Header, message::Message, query::Query};
-use std::io;
+use pretty_dns_message::{
+    edns::Edns,
+    header::{Header, RCode},
+    message::Message,
+    query::Query,
+};
+use rand::Rng;
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
 use tokio::{
-    net::{ToSocketAddrs, UdpSocket},
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
     time::{self, Duration},
 };
+use tracing::{debug, warn};
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// UDP payload size advertised with EDNS, which is also the size of the
+    /// receive buffer.
+    pub udp_payload_size: u16,
+    pub timeout: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            udp_payload_size: 1232,
+            timeout: Duration::from_secs(3),
+        }
+    }
+}
 
-pub async fn resolve<T: ToSocketAddrs>(query: Query, ns: T) -> io::Result<Message> {
-    let sock = UdpSocket::bind("0.0.0.0:0").await?;
+pub async fn resolve(query: Query, ns: SocketAddr, c: &Config) -> io::Result<Message> {
     let message = Message {
         header: Header {
-            id: 41693,
+            id: rand::random(),
             qr: 0,
             opcode: 0,
             aa: 0,
@@ -19,7 +46,7 @@ pub async fn resolve<T: ToSocketAddrs>(query: Query, ns: T) -> io::Result<Messag
             z: 0,
             ad: 1,
             cd: 0,
-            rcode: 0,
+            rcode: RCode::NoError,
             qd_count: 1,
             an_count: 0,
             ns_count: 0,
@@ -29,43 +56,260 @@ pub async fn resolve<T: ToSocketAddrs>(query: Query, ns: T) -> io::Result<Messag
         answer: vec![],
         authority: vec![],
         additional: vec![],
+        edns: Some(Edns::new(c.udp_payload_size)),
     };
 
-    sock.send_to(&message.to_vec().await?, ns).await?;
+    exchange(&message, ns, c).await
+}
 
-    let result = time::timeout(Duration::from_secs(3), async {
-        let mut buf = [0; 1024];
+/// Send a request from a client to `ns` as it is, and return the response
+/// with the id of the request.
+pub async fn forward(mut req: Message, ns: SocketAddr, c: &Config) -> io::Result<Message> {
+    // the id chosen by the client is not used upstream
+    let id = req.header.id;
+    req.header.id = rand::random();
 
-        match sock.recv_from(&mut buf).await {
-            Ok((v, _)) => {
-                let (_, res) = Message::from_bytes(&buf).unwrap();
-                return Ok(res);
-            }
-            Err(v) => return Err(v),
-        }
-    })
-    .await?;
+    // the response has to fit in our buffer rather than the client's
+    req.edns = Some(Edns {
+        dnssec_ok: req.edns.map_or(false, |v| v.dnssec_ok),
+        ..Edns::new(c.udp_payload_size)
+    });
 
-    return result;
+    let mut res = exchange(&req, ns, c).await?;
+    res.header.id = id;
+
+    Ok(res)
 }
 
-pub async fn forward(req: Message) -> io::Result<Vec<u8>> {
-    let sock = UdpSocket::bind("0.0.0.0:0").await?;
+/// Send a query over UDP, and retry it over TCP when the response is truncated.
+async fn exchange(req: &Message, ns: SocketAddr, c: &Config) -> io::Result<Message> {
     let data = req.to_vec().await?;
-    sock.send_to(&data, "8.8.8.8:53").await?;
 
-    let result = time::timeout(Duration::from_secs(3), async {
-        let mut buf = [0; 1024];
+    let res = exchange_udp(req, &data, ns, c).await?;
+    if res.header.tc == 0 {
+        return Ok(res);
+    }
+
+    debug!("response is truncated, retry over tcp: {:?}", ns);
+    exchange_tcp(req, &data, ns, c).await
+}
+
+async fn exchange_udp(
+    req: &Message,
+    data: &[u8],
+    ns: SocketAddr,
+    c: &Config,
+) -> io::Result<Message> {
+    let sock = bind_random_port(ns).await?;
+    sock.send_to(data, ns).await?;
+
+    time::timeout(c.timeout, async {
+        // the responder must not send more than the payload size we advertised
+        let mut buf = vec![0; Edns::new(c.udp_payload_size).payload_size().into()];
 
-        match sock.recv_from(&mut buf).await {
-            Ok(v) => {
-                let (_, res) = Message::from_bytes(&buf).unwrap();
-                return Ok(res.to_vec().await?);
+        // keep waiting for the real response when a spoofed one arrives first
+        loop {
+            let (len, from) = sock.recv_from(&mut buf).await?;
+            if from != ns {
+                warn!(
+                    "discard response from unexpected address: {:?} {:?}",
+                    from, ns
+                );
+                continue;
             }
-            Err(v) => return Err(v),
+
+            let res = match parse(&buf[..len]) {
+                Ok(res) => res,
+                Err(e) => {
+                    warn!("discard response: {:?} {:?}", ns, e);
+                    continue;
+                }
+            };
+
+            match validate(req, &res) {
+                Ok(()) => return Ok(res),
+                Err(reason) => warn!("discard response, {}: {:?}", reason, ns),
+            }
+        }
+    })
+    .await?
+}
+
+async fn exchange_tcp(
+    req: &Message,
+    data: &[u8],
+    ns: SocketAddr,
+    c: &Config,
+) -> io::Result<Message> {
+    time::timeout(c.timeout, async {
+        let mut stream = TcpStream::connect(ns).await?;
+        stream.write_u16(data.len() as u16).await?;
+        stream.write_all(data).await?;
+
+        let len = stream.read_u16().await?;
+        let mut buf = vec![0; len.into()];
+        stream.read_exact(&mut buf).await?;
+
+        let res = parse(&buf)?;
+        if let Err(reason) = validate(req, &res) {
+            warn!("discard response, {}: {:?}", reason, ns);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, reason));
         }
+
+        Ok(res)
     })
-    .await?;
+    .await?
+}
+
+/// Bind a socket to a random source port, so that a spoofed response has to
+/// guess the port as well as the id. The address family is the same as `ns`.
+async fn bind_random_port(ns: SocketAddr) -> io::Result<UdpSocket> {
+    let addr: IpAddr = match ns {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+
+    for _ in 0..10 {
+        let port = rand::thread_rng().gen_range(1024..=u16::MAX);
+        match UdpSocket::bind((addr, port)).await {
+            Ok(sock) => return Ok(sock),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    UdpSocket::bind((addr, 0)).await
+}
+
+/// Check that the response answers the query which was sent.
+fn validate(req: &Message, res: &Message) -> Result<(), &'static str> {
+    if res.header.id != req.header.id {
+        return Err("id mismatch");
+    }
+
+    if res.header.qr != 1 {
+        return Err("not a response");
+    }
+
+    if res.query != req.query {
+        return Err("question mismatch");
+    }
+
+    Ok(())
+}
+
+fn parse(buf: &[u8]) -> io::Result<Message> {
+    Message::from_bytes(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve, Config};
+    use pretty_dns_message::{
+        domain::Domain,
+        message::Message,
+        qtype::QType,
+        query::Query,
+        resource::{RData, Resource},
+    };
+    use std::net::Ipv4Addr;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, UdpSocket},
+    };
+
+    #[tokio::test]
+    async fn test_resolve_tcp_fallback() {
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind(addr).await.unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (len, from) = udp.recv_from(&mut buf).await.unwrap();
+            let mut res = Message::from_bytes(&buf[..len]).unwrap();
+            res.header.qr = 1;
+            res.header.tc = 1;
+            udp.send_to(&res.to_vec().await.unwrap(), from)
+                .await
+                .unwrap();
+        });
+
+        tokio::spawn(async move {
+            let (mut stream, _) = tcp.accept().await.unwrap();
+            let len = stream.read_u16().await.unwrap();
+            let mut buf = vec![0; len.into()];
+            stream.read_exact(&mut buf).await.unwrap();
+
+            let mut res = Message::from_bytes(&buf).unwrap();
+            res.header.qr = 1;
+            res.answer.push(Resource {
+                name: Domain::from("example.com."),
+                _type: QType::A,
+                class: 1,
+                ttl: 300,
+                rdata: RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            });
+
+            let data = res.to_vec().await.unwrap();
+            stream.write_u16(data.len() as u16).await.unwrap();
+            stream.write_all(&data).await.unwrap();
+        });
+
+        let query = Query {
+            qname: Domain::from("example.com."),
+            qtype: QType::A,
+            qclass: 1,
+        };
+        let res = resolve(query, addr, &Config::default()).await.unwrap();
+
+        assert_eq!(res.header.tc, 0);
+        assert_eq!(res.answer.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_discard_mismatched_response() {
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = udp.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (len, from) = udp.recv_from(&mut buf).await.unwrap();
+            let mut res = Message::from_bytes(&buf[..len]).unwrap();
+            res.header.qr = 1;
+
+            let mut spoofed = Message::from_bytes(&buf[..len]).unwrap();
+            spoofed.header.qr = 1;
+            spoofed.header.id = res.header.id.wrapping_add(1);
+            udp.send_to(&spoofed.to_vec().await.unwrap(), from)
+                .await
+                .unwrap();
+
+            spoofed.header.id = res.header.id;
+            spoofed.query.as_mut().unwrap().qtype = QType::AAAA;
+            udp.send_to(&spoofed.to_vec().await.unwrap(), from)
+                .await
+                .unwrap();
+
+            res.answer.push(Resource {
+                name: Domain::from("example.com."),
+                _type: QType::A,
+                class: 1,
+                ttl: 300,
+                rdata: RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            });
+            udp.send_to(&res.to_vec().await.unwrap(), from)
+                .await
+                .unwrap();
+        });
+
+        let query = Query {
+            qname: Domain::from("example.com."),
+            qtype: QType::A,
+            qclass: 1,
+        };
+        let res = resolve(query, addr, &Config::default()).await.unwrap();
 
-    return result;
+        assert_eq!(res.answer.len(), 1);
+    }
 }
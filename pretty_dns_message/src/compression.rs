@@ -1,11 +1,17 @@
+use crate::{
+    domain::Domain,
+    error::{IResult, MessageError},
+};
 use nom::{
-    bytes::complete::take,
     combinator::peek,
-    combinator::{cond, flat_map},
-    multi::fold_many0,
-    number::complete::be_u8,
-    IResult,
+    multi::length_data,
+    number::complete::{be_u16, be_u8},
 };
+use std::collections::HashMap;
+
+/// A pointer is the two high bits set followed by a 14 bit offset.
+const POINTER_FLAG: u16 = 0b1100_0000_0000_0000;
+const POINTER_MASK: u16 = 0b0011_1111_1111_1111;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct CompressionData {
@@ -15,7 +21,7 @@ pub struct CompressionData {
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum DataType {
-    Compression { position: u8 },
+    Compression { position: u16 },
     Raw(Vec<u8>),
 }
 
@@ -30,91 +36,73 @@ impl CompressionData {
         CompressionData { inner, _type }
     }
 
-    pub fn from_domain<'a>(raw: &'a [u8]) -> IResult<&'a [u8], CompressionData> {
+    pub fn from_domain(raw: &[u8]) -> IResult<&[u8], CompressionData> {
         let (data, result) = Self::from(raw)?;
 
         Ok((data, CompressionData::new(result, CompressionType::Domain)))
     }
 
-    fn from<'a>(raw: &'a [u8]) -> IResult<&'a [u8], Vec<DataType>> {
+    fn from(raw: &[u8]) -> IResult<&[u8], Vec<DataType>> {
         let mut result = vec![];
 
-        let mut data = raw.clone();
+        let mut data = raw;
         loop {
             let (_, flag) = peek(be_u8)(data)?;
-            if (flag >> 6) == 3 {
-                let (_data, _) = be_u8(data)?;
-                let (_data, m2) = be_u8(_data)?;
-                data = _data;
-
-                result.push(DataType::Compression { position: m2 });
-                break;
-            } else if flag != 0 {
-                let (_, end) = be_u8(data)?;
-                let (remain, _data) = take(end as usize + 1)(data)?;
-                let (_, domain) = Self::read_domain(false)(_data)?;
-
-                data = remain;
-                result.push(DataType::Raw(domain));
-            } else {
-                let (_data, _) = be_u8(data)?;
-                data = _data;
-                break;
+            match flag >> 6 {
+                3 => {
+                    let (_data, pointer) = be_u16(data)?;
+                    data = _data;
+
+                    result.push(DataType::Compression {
+                        position: pointer & POINTER_MASK,
+                    });
+                    break;
+                }
+                0 if flag != 0 => {
+                    let (_data, label) = length_data(be_u8)(data)?;
+                    data = _data;
+
+                    result.push(DataType::Raw(label.to_vec()));
+                }
+                0 => {
+                    let (_data, _) = be_u8(data)?;
+                    data = _data;
+                    break;
+                }
+                // 0b01 and 0b10 are reserved, RFC 1035 4.1.4
+                _ => return Err(nom::Err::Failure(MessageError::BadLabelLength)),
             }
         }
 
         Ok((data, result))
     }
 
-    fn read_domain(is_check_last_zero: bool) -> impl FnMut(&[u8]) -> IResult<&[u8], Vec<u8>> {
-        move |data: &[u8]| {
-            let (data, qname) = fold_many0(
-                Self::_read_domain,
-                Vec::new,
-                |mut v: Vec<_>, item: &[u8]| {
-                    let item = item.to_vec();
-                    v.push(item);
-                    v
-                },
-            )(data)?;
-
-            let (data, z) = cond(is_check_last_zero, be_u8)(data)?;
-            if let Some(z) = z {
-                // read 0 which is the end of the qname
-                if z != 0 {
-                    return Err(nom::Err::Incomplete(nom::Needed::new(0)));
-                }
-            }
-
-            return Ok((data, qname.into_iter().flatten().collect()));
-        }
+    pub fn labels(&self) -> Vec<Vec<u8>> {
+        self.inner
+            .iter()
+            .filter_map(|v| match v {
+                DataType::Raw(label) => Some(label.clone()),
+                DataType::Compression { .. } => None,
+            })
+            .collect()
     }
 
-    fn _read_domain(data: &[u8]) -> IResult<&[u8], &[u8]> {
-        let (data, a) = flat_map(be_u8, take)(data)?;
-        if a.len() == 0 {
-            return Err(nom::Err::Error(nom::error::make_error(
-                data,
-                nom::error::ErrorKind::Eof,
-            )));
+    pub fn pointer(&self) -> Option<u16> {
+        match self.inner.last() {
+            Some(DataType::Compression { position }) => Some(*position),
+            _ => None,
         }
-
-        return Ok((data, a));
     }
 
     pub fn into(self) -> Vec<u8> {
-        let mut is_append_zero = false;
-        if let CompressionType::Domain = self._type {
-            if let Some(DataType::Raw(_)) = self.inner.last() {
-                is_append_zero = true;
-            }
-        }
+        // a domain which does not end with a pointer needs the root label
+        let is_append_zero = self._type == CompressionType::Domain
+            && !matches!(self.inner.last(), Some(DataType::Compression { .. }));
 
         let mut result: Vec<_> = self
             .inner
             .into_iter()
-            .map::<Vec<u8>, _>(|v| v.into(&self._type))
-            .flatten()
+            .flat_map(|v| v.into(&self._type))
             .collect();
 
         if is_append_zero {
@@ -128,9 +116,7 @@ impl CompressionData {
 impl DataType {
     fn into(self, _type: &CompressionType) -> Vec<u8> {
         match self {
-            DataType::Compression { position } => {
-                vec![192, position]
-            }
+            DataType::Compression { position } => (POINTER_FLAG | position).to_be_bytes().to_vec(),
             DataType::Raw(v) => {
                 let mut result = if let CompressionType::Domain = _type {
                     vec![v.len() as u8]
@@ -145,9 +131,99 @@ impl DataType {
     }
 }
 
+/// Encoder builds a whole message and remembers the offset of every name
+/// suffix it has written, so that later names can be replaced by pointers
+/// as described in RFC 1035 4.1.4.
+#[derive(Debug, Default)]
+pub struct Encoder {
+    buf: Vec<u8>,
+    names: HashMap<Vec<Vec<u8>>, u16>,
+    disable_compression: bool,
+}
+
+impl Encoder {
+    pub fn new() -> Encoder {
+        Encoder::default()
+    }
+
+    /// Encoder for data which is not written at its final position in a
+    /// message, where pointers would be meaningless.
+    pub fn without_compression() -> Encoder {
+        Encoder {
+            disable_compression: true,
+            ..Encoder::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn write_u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    pub fn write_u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    pub fn write_u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    pub fn write_all(&mut self, v: &[u8]) {
+        self.buf.extend_from_slice(v);
+    }
+
+    /// Overwrite a u16 which has already been written, e.g. RDLENGTH.
+    pub fn set_u16(&mut self, position: usize, v: u16) {
+        self.buf[position..position + 2].copy_from_slice(&v.to_be_bytes());
+    }
+
+    /// Write a domain name. When `compress` is set, the longest suffix that
+    /// has already been written is replaced by a pointer.
+    pub fn write_name(&mut self, name: &Domain, compress: bool) {
+        let compress = compress && !self.disable_compression;
+        let labels = name.wire_labels();
+
+        let mut inner = vec![];
+        let mut position = self.buf.len();
+        for (i, label) in labels.iter().enumerate() {
+            if compress {
+                if let Some(&pointer) = self.names.get(&labels[i..]) {
+                    inner.push(DataType::Compression { position: pointer });
+                    break;
+                }
+
+                // offsets beyond 14 bits can not be pointed to
+                if let Ok(position) = u16::try_from(position) {
+                    if position <= POINTER_MASK {
+                        self.names.insert(labels[i..].to_vec(), position);
+                    }
+                }
+            }
+
+            position += label.len() + 1;
+            inner.push(DataType::Raw(label.clone()));
+        }
+
+        self.buf
+            .extend(CompressionData::new(inner, CompressionType::Domain).into());
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{CompressionData, CompressionType, DataType};
+    use super::{CompressionData, CompressionType, DataType, Encoder};
+    use crate::{domain::Domain, error::MessageError};
 
     #[tokio::test]
     async fn test_read_normal() {
@@ -201,6 +277,13 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_read_reserved_label() {
+        let data = vec![65, 97, 0];
+        let result = CompressionData::from_domain(&data);
+        assert_eq!(result, Err(nom::Err::Failure(MessageError::BadLabelLength)));
+    }
+
     #[tokio::test]
     async fn test_into() {
         let data: CompressionData = CompressionData::new(
@@ -229,4 +312,84 @@ mod tests {
             vec![6, 103, 111, 111, 103, 108, 101, 3, 99, 111, 109, 0]
         );
     }
+
+    #[tokio::test]
+    async fn test_into_root() {
+        let data = CompressionData::new(vec![], CompressionType::Domain);
+        let result: Vec<u8> = data.into();
+        assert_eq!(result, vec![0]);
+    }
+
+    #[tokio::test]
+    async fn test_encoder_compress_suffix() {
+        let google = Domain::from("google.com.");
+        let www = Domain::from("www.google.com.");
+
+        let mut e = Encoder::new();
+        e.write_all(&[0; 12]);
+        e.write_name(&google, true);
+        e.write_name(&www, true);
+        e.write_name(&google, true);
+        e.write_name(&www, false);
+
+        let result = e.into_vec();
+        assert_eq!(
+            result[12..],
+            [
+                6, 103, 111, 111, 103, 108, 101, 3, 99, 111, 109, 0, 3, 119, 119, 119, 192, 12,
+                192, 12, 3, 119, 119, 119, 6, 103, 111, 111, 103, 108, 101, 3, 99, 111, 109, 0
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_encoder_without_compression() {
+        let name = Domain::from("com.");
+
+        let mut e = Encoder::without_compression();
+        e.write_name(&name, true);
+        e.write_name(&name, true);
+
+        assert_eq!(e.into_vec(), vec![3, 99, 111, 109, 0, 3, 99, 111, 109, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_read_compression_large_offset() {
+        let data = vec![1, 98, 193, 44];
+        let (data, result) = CompressionData::from_domain(&data).unwrap();
+        assert_eq!(data, vec![]);
+        assert_eq!(
+            result,
+            CompressionData::new(
+                vec![
+                    DataType::Raw(vec![98]),
+                    DataType::Compression { position: 300 }
+                ],
+                CompressionType::Domain
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_into_large_offset() {
+        let data = CompressionData::new(
+            vec![DataType::Compression { position: 0x3fff }],
+            CompressionType::Domain,
+        );
+        let result: Vec<u8> = data.into();
+        assert_eq!(result, vec![255, 255]);
+    }
+
+    #[tokio::test]
+    async fn test_encoder_large_offset() {
+        let name = Domain::from("example.com.");
+
+        let mut e = Encoder::new();
+        e.write_all(&[0; 300]);
+        e.write_name(&name, true);
+        e.write_name(&name, true);
+
+        let result = e.into_vec();
+        assert_eq!(result[313..], [193, 44]);
+    }
 }
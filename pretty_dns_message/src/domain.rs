@@ -1,11 +1,12 @@
-use nom::{
-    bytes::complete::take,
-    combinator::peek,
-    combinator::{cond, flat_map},
-    multi::fold_many0,
-    number::complete::be_u8,
-    IResult,
+use crate::{
+    compression::CompressionData,
+    error::{IResult, MessageError},
 };
+use nom::Offset;
+use std::fmt;
+
+/// The maximum length of a domain name in wire format, RFC 1035 2.3.4.
+const MAX_DOMAIN_LENGTH: usize = 255;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Domain(Vec<u8>);
@@ -15,75 +16,81 @@ where
     T: Into<Vec<u8>>,
 {
     fn from(data: T) -> Domain {
-        return Domain(data.into());
+        Domain(data.into())
     }
 }
 
-impl ToString for Domain {
-    fn to_string(&self) -> String {
-        String::from_utf8(self.0.clone()).unwrap()
+impl fmt::Display for Domain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.0))
     }
 }
 
 impl Domain {
-    pub fn read(data: &[u8]) -> IResult<&[u8], Domain> {
-        let (data, m1) = peek(be_u8)(data)?;
-
-        // check message compaction
-        if (m1 >> 6) == 3 {
-            let (data, m1) = be_u8(data)?;
-            let (data, m2) = be_u8(data)?;
-
-            return Ok((data, Domain::from(vec![m1, m2])));
-        } else {
-            let (data, domain) = Domain::read_domain(true)(data)?;
-            return Ok((data, Domain::from(domain)));
-        }
-    }
+    /// Read a domain and follow its compression pointers against `message`,
+    /// which must be the whole message that `data` is a part of.
+    /// Every pointer has to point before the previous jump, so forward
+    /// pointers and loops are rejected.
+    pub fn read<'a>(message: &'a [u8]) -> impl FnMut(&'a [u8]) -> IResult<&'a [u8], Domain> {
+        move |data: &'a [u8]| {
+            let (remain, mut name) = CompressionData::from_domain(data)?;
+            let mut limit = message.offset(remain).saturating_sub(2);
 
-    pub fn read_domain(is_check_last_zero: bool) -> impl FnMut(&[u8]) -> IResult<&[u8], Vec<u8>> {
-        move |data: &[u8]| {
-            let (data, qname) = fold_many0(
-                Domain::_read_domain,
-                Vec::new,
-                |mut v: Vec<_>, item: &[u8]| {
-                    let mut item = item.to_vec();
-                    item.push(46);
-                    v.push(item);
-                    v
-                },
-            )(data)?;
-
-            let (data, z) = cond(is_check_last_zero, be_u8)(data)?;
-            if let Some(z) = z {
-                // read 0 which is the end of the qname
-                if z != 0 {
-                    return Err(nom::Err::Incomplete(nom::Needed::new(0)));
+            let mut labels = name.labels();
+            while let Some(position) = name.pointer() {
+                let position = position as usize;
+                if position >= limit {
+                    return Err(nom::Err::Failure(MessageError::PointerLoop));
                 }
+
+                let (_, target) = CompressionData::from_domain(&message[position..])?;
+                limit = position;
+                labels.extend(target.labels());
+                name = target;
             }
 
-            return Ok((data, qname.into_iter().flatten().collect()));
+            let length = labels.iter().map(|v| v.len() + 1).sum::<usize>() + 1;
+            if length > MAX_DOMAIN_LENGTH {
+                return Err(nom::Err::Failure(MessageError::BadLabelLength));
+            }
+
+            let labels: Vec<Vec<u8>> = labels.iter().map(|v| escape(v)).collect();
+            Ok((remain, Domain::from_labels(&labels)))
         }
     }
 
-    fn _read_domain(data: &[u8]) -> IResult<&[u8], &[u8]> {
-        let (data, a) = flat_map(be_u8, take)(data)?;
-        if a.len() == 0 {
-            return Err(nom::Err::Error(nom::error::make_error(
-                data,
-                nom::error::ErrorKind::Eof,
-            )));
+    pub fn from_labels(labels: &[Vec<u8>]) -> Domain {
+        if labels.is_empty() {
+            return Domain::from(".");
+        }
+
+        let mut domain = vec![];
+        for v in labels {
+            domain.extend_from_slice(v);
+            domain.push(b'.');
         }
 
-        return Ok((data, a));
+        Domain(domain)
     }
 
+    /// Split the domain at `c`, except where it is escaped with a backslash.
     pub fn split(&self, c: char) -> Vec<Vec<u8>> {
         let c = c as u8;
         let mut result = vec![];
         let mut data = vec![];
+        let mut escaped = false;
         for v in &self.0 {
             let v = *v;
+            if escaped {
+                escaped = false;
+                data.push(v);
+                continue;
+            }
+            if v == b'\\' {
+                escaped = true;
+                data.push(v);
+                continue;
+            }
             if v == c {
                 result.push(data);
                 data = vec![];
@@ -94,47 +101,172 @@ impl Domain {
 
         result.push(data);
 
-        return result;
+        result
     }
 
-    pub fn to_vec(&self) -> Vec<u8> {
-        let mut qname = vec![];
-        if self.is_compression() {
-            qname.extend_from_slice(self.0.as_ref());
-            return qname;
+    /// Labels of the domain without the empty root label, as they are
+    /// sent on the wire with the escapes removed.
+    pub fn wire_labels(&self) -> Vec<Vec<u8>> {
+        self.labels().iter().map(|v| unescape(v)).collect()
+    }
+
+    /// Labels of the domain without the empty root label. A `.` or `\\`
+    /// inside a label is escaped with a backslash.
+    pub fn labels(&self) -> Vec<Vec<u8>> {
+        self.split('.')
+            .into_iter()
+            .filter(|v| !v.is_empty())
+            .collect()
+    }
+
+    /// Compare two domains ignoring the case of ASCII letters, RFC 4343.
+    pub fn eq_ignore_case(&self, other: &Domain) -> bool {
+        self.to_lowercase().labels() == other.to_lowercase().labels()
+    }
+
+    /// Whether the domain is `parent` or a name below it.
+    pub fn is_subdomain_of(&self, parent: &Domain) -> bool {
+        let labels = self.to_lowercase().labels();
+        let parent = parent.to_lowercase().labels();
+
+        labels.ends_with(&parent)
+    }
+
+    /// Replace the `suffix` of the domain with `target`, which is how a
+    /// DNAME rewrites a name (RFC 6672 2.2). Returns `None` when the domain
+    /// is not below `suffix`.
+    pub fn replace_suffix(&self, suffix: &Domain, target: &Domain) -> Option<Domain> {
+        if !self.is_subdomain_of(suffix) {
+            return None;
         }
 
-        for v in self.split('.') {
+        let mut labels = self.labels();
+        labels.truncate(labels.len() - suffix.labels().len());
+        labels.extend(target.labels());
+
+        Some(Domain::from_labels(&labels))
+    }
+
+    fn to_lowercase(&self) -> Domain {
+        Domain(self.0.to_ascii_lowercase())
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut qname = vec![];
+        for v in self.wire_labels() {
             qname.push(v.len() as u8);
             qname.extend_from_slice(v.as_ref());
         }
+        qname.push(0);
 
-        return qname;
+        qname
     }
+}
 
-    pub fn is_compression(&self) -> bool {
-        return (self.0[0] >> 6) == 3;
+/// Labels are binary (RFC 2181 11), so the bytes which separate labels in
+/// the text form are escaped as in the presentation format of RFC 1035 5.1.
+fn escape(label: &[u8]) -> Vec<u8> {
+    let mut result = vec![];
+    for v in label {
+        if *v == b'.' || *v == b'\\' {
+            result.push(b'\\');
+        }
+        result.push(*v);
     }
+
+    result
+}
+
+fn unescape(label: &[u8]) -> Vec<u8> {
+    let mut result = vec![];
+    let mut escaped = false;
+    for v in label {
+        if *v == b'\\' && !escaped {
+            escaped = true;
+            continue;
+        }
+        escaped = false;
+        result.push(*v);
+    }
+
+    result
 }
 
 #[cfg(test)]
 mod tests {
     use super::Domain;
+    use crate::error::MessageError;
 
     #[tokio::test]
     async fn test_read() {
-        let (_, domain) =
-            Domain::read(&vec![6, 103, 111, 111, 103, 108, 101, 3, 99, 111, 109, 0]).unwrap();
+        let data = vec![6, 103, 111, 111, 103, 108, 101, 3, 99, 111, 109, 0];
+        let (_, domain) = Domain::read(&data)(&data).unwrap();
         assert_eq!(
             domain,
             Domain(vec![103, 111, 111, 103, 108, 101, 46, 99, 111, 109, 46]),
         );
     }
 
+    #[tokio::test]
+    async fn test_read_root() {
+        let data = vec![0];
+        let (_, domain) = Domain::read(&data)(&data).unwrap();
+        assert_eq!(domain, Domain::from("."));
+    }
+
     #[tokio::test]
     async fn test_compression_read() {
-        let (_, domain) = Domain::read(&vec![192, 12]).unwrap();
-        assert_eq!(domain, Domain(vec![192, 12]));
+        let data = vec![
+            6, 103, 111, 111, 103, 108, 101, 3, 99, 111, 109, 0, 3, 119, 119, 119, 192, 0, 192, 12,
+        ];
+        let (remain, domain) = Domain::read(&data)(&data[12..]).unwrap();
+        assert_eq!(domain, Domain::from("www.google.com."));
+        assert_eq!(remain, vec![192, 12]);
+
+        let (remain, domain) = Domain::read(&data)(remain).unwrap();
+        assert_eq!(domain, Domain::from("www.google.com."));
+        assert_eq!(remain, vec![]);
+    }
+
+    #[tokio::test]
+    async fn test_compression_read_loop() {
+        let data = vec![1, 97, 192, 0];
+        assert_eq!(
+            Domain::read(&data)(&data),
+            Err(nom::Err::Failure(MessageError::PointerLoop))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compression_read_forward() {
+        let data = vec![192, 2, 1, 97, 0];
+        assert_eq!(
+            Domain::read(&data)(&data),
+            Err(nom::Err::Failure(MessageError::PointerLoop))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_binary_label() {
+        // labels are not required to be utf-8
+        let data = vec![2, 0xc3, 0x28, 0];
+        let (_, domain) = Domain::read(&data)(&data).unwrap();
+        assert_eq!(domain, Domain(vec![0xc3, 0x28, 46]));
+        assert_eq!(domain.to_vec(), data);
+
+        // a dot inside a label is escaped
+        let data = vec![3, 97, 46, 98, 1, 99, 0];
+        let (_, domain) = Domain::read(&data)(&data).unwrap();
+        assert_eq!(domain, Domain(b"a\\.b.c.".to_vec()));
+        assert_eq!(domain.labels().len(), 2);
+        assert_eq!(domain.wire_labels()[0], b"a.b".to_vec());
+        assert_eq!(domain.to_vec(), data);
+    }
+
+    #[tokio::test]
+    async fn test_display_invalid_utf8() {
+        let domain = Domain::from(vec![0xc3, 0x28, 46]);
+        assert_eq!(domain.to_string(), "\u{fffd}(.");
     }
 
     #[tokio::test]
@@ -151,6 +283,29 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_is_subdomain_of() {
+        let domain = Domain::from("www.Example.com.");
+        assert!(domain.is_subdomain_of(&Domain::from("example.COM.")));
+        assert!(domain.is_subdomain_of(&Domain::from("www.example.com.")));
+        assert!(domain.is_subdomain_of(&Domain::from(".")));
+        assert!(!domain.is_subdomain_of(&Domain::from("ample.com.")));
+        assert!(domain.eq_ignore_case(&Domain::from("WWW.example.com.")));
+    }
+
+    #[tokio::test]
+    async fn test_replace_suffix() {
+        let domain = Domain::from("www.example.com.");
+        assert_eq!(
+            domain.replace_suffix(&Domain::from("example.com."), &Domain::from("example.net.")),
+            Some(Domain::from("www.example.net."))
+        );
+        assert_eq!(
+            domain.replace_suffix(&Domain::from("example.org."), &Domain::from("example.net.")),
+            None
+        );
+    }
+
     #[tokio::test]
     async fn test_to_vec() {
         let domain = Domain(b"google.com.".to_vec());
@@ -162,9 +317,9 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_compression_to_vec() {
-        let domain = Domain(vec![192, 12]);
+    async fn test_root_to_vec() {
+        let domain = Domain::from(".");
         let result = domain.to_vec();
-        assert_eq!(result, [192, 12]);
+        assert_eq!(result, [0]);
     }
 }
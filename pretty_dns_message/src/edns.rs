@@ -0,0 +1,166 @@
+use crate::{
+    domain::Domain,
+    error::{IResult, MessageError},
+    qtype::QType,
+    resource::{RData, Resource},
+};
+use nom::{
+    multi::{length_data, many0},
+    number::complete::be_u16,
+    sequence::pair,
+};
+
+/// The payload size assumed for clients which do not use EDNS, RFC 1035 4.2.1.
+pub const DEFAULT_UDP_PAYLOAD_SIZE: u16 = 512;
+
+const DNSSEC_OK: u32 = 0b1000_0000_0000_0000;
+
+/// EDNS(0) information carried by an OPT pseudo record, RFC 6891 6.1.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Edns {
+    pub udp_payload_size: u16,
+    pub extended_rcode: u8,
+    pub version: u8,
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+impl Edns {
+    pub fn new(udp_payload_size: u16) -> Edns {
+        Edns {
+            udp_payload_size,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: vec![],
+        }
+    }
+
+    /// Payload sizes below 512 are treated as 512, RFC 6891 6.2.3.
+    pub fn payload_size(&self) -> u16 {
+        self.udp_payload_size.max(DEFAULT_UDP_PAYLOAD_SIZE)
+    }
+
+    pub fn from_resource(r: &Resource) -> Option<Edns> {
+        if r._type != QType::OPT {
+            return None;
+        }
+
+        let options = match r.rdata {
+            RData::Unknown(ref data) => {
+                let (_, options) = Edns::read_options(data).ok()?;
+                options
+            }
+            _ => return None,
+        };
+
+        Some(Edns {
+            udp_payload_size: r.class,
+            extended_rcode: (r.ttl >> 24) as u8,
+            version: (r.ttl >> 16) as u8,
+            dnssec_ok: (r.ttl & DNSSEC_OK) != 0,
+            options,
+        })
+    }
+
+    fn read_options(data: &[u8]) -> IResult<&[u8], Vec<EdnsOption>> {
+        let (data, options) = many0(pair(be_u16, length_data(be_u16)))(data)?;
+        if !data.is_empty() {
+            return Err(nom::Err::Failure(MessageError::Truncated));
+        }
+
+        Ok((
+            data,
+            options
+                .into_iter()
+                .map(|(code, data)| EdnsOption {
+                    code,
+                    data: data.to_vec(),
+                })
+                .collect(),
+        ))
+    }
+
+    pub fn to_resource(&self) -> Resource {
+        let mut ttl = (self.extended_rcode as u32) << 24 | (self.version as u32) << 16;
+        if self.dnssec_ok {
+            ttl |= DNSSEC_OK;
+        }
+
+        let mut rdata = vec![];
+        for v in &self.options {
+            rdata.extend_from_slice(&v.code.to_be_bytes());
+            rdata.extend_from_slice(&(v.data.len() as u16).to_be_bytes());
+            rdata.extend_from_slice(&v.data);
+        }
+
+        Resource {
+            name: Domain::from("."),
+            _type: QType::OPT,
+            class: self.udp_payload_size,
+            ttl,
+            rdata: RData::Unknown(rdata),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Edns, EdnsOption};
+    use crate::{
+        domain::Domain,
+        qtype::QType,
+        resource::{RData, Resource},
+    };
+
+    #[tokio::test]
+    async fn test_from_resource() {
+        let r = Resource {
+            name: Domain::from("."),
+            _type: QType::OPT,
+            class: 4096,
+            ttl: 0x0100_8000,
+            rdata: RData::Unknown(vec![0, 10, 0, 2, 1, 2]),
+        };
+
+        assert_eq!(
+            Edns::from_resource(&r),
+            Some(Edns {
+                udp_payload_size: 4096,
+                extended_rcode: 1,
+                version: 0,
+                dnssec_ok: true,
+                options: vec![EdnsOption {
+                    code: 10,
+                    data: vec![1, 2],
+                }],
+            })
+        );
+        assert_eq!(Edns::from_resource(&r).unwrap().to_resource(), r);
+    }
+
+    #[tokio::test]
+    async fn test_from_resource_broken_option() {
+        let r = Resource {
+            name: Domain::from("."),
+            _type: QType::OPT,
+            class: 4096,
+            ttl: 0,
+            rdata: RData::Unknown(vec![0, 10, 0, 4, 1, 2]),
+        };
+
+        assert_eq!(Edns::from_resource(&r), None);
+    }
+
+    #[tokio::test]
+    async fn test_payload_size() {
+        assert_eq!(Edns::new(100).payload_size(), 512);
+        assert_eq!(Edns::new(1232).payload_size(), 1232);
+    }
+}
@@ -0,0 +1,57 @@
+use nom::error::{ErrorKind, ParseError};
+use std::fmt;
+
+/// The result of the parsers in this crate.
+pub type IResult<I, O> = nom::IResult<I, O, MessageError>;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MessageError {
+    /// The message ended in the middle of a field.
+    Truncated,
+    /// A label has one of the reserved types or the domain is longer than 255 octets.
+    BadLabelLength,
+    /// A compression pointer which does not point before the previous one.
+    PointerLoop,
+    /// The sections do not have as many entries as the header says.
+    CountMismatch,
+    /// RDATA which is not as long as RDLENGTH.
+    BadRdata,
+    TrailingBytes,
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            MessageError::Truncated => "message is truncated",
+            MessageError::BadLabelLength => "bad label length",
+            MessageError::PointerLoop => "compression pointer loop",
+            MessageError::CountMismatch => "section count mismatch",
+            MessageError::BadRdata => "rdata does not match rdlength",
+            MessageError::TrailingBytes => "trailing bytes after the message",
+        };
+
+        write!(f, "{}", s)
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+impl<I> ParseError<I> for MessageError {
+    // all the parsers are complete, so they only fail when the input runs out
+    fn from_error_kind(_: I, _: ErrorKind) -> MessageError {
+        MessageError::Truncated
+    }
+
+    fn append(_: I, _: ErrorKind, other: MessageError) -> MessageError {
+        other
+    }
+}
+
+impl From<nom::Err<MessageError>> for MessageError {
+    fn from(e: nom::Err<MessageError>) -> MessageError {
+        match e {
+            nom::Err::Error(e) | nom::Err::Failure(e) => e,
+            nom::Err::Incomplete(_) => MessageError::Truncated,
+        }
+    }
+}
@@ -1,9 +1,10 @@
+use crate::error::IResult;
 use bitflags::bitflags;
-use nom::{number::complete::be_u16, IResult};
+use nom::number::complete::be_u16;
 use std::ops::BitAnd;
 use tokio::io::AsyncWriteExt;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Header {
     pub id: u16,
     pub qr: u8,
@@ -15,13 +16,53 @@ pub struct Header {
     pub z: u8,
     pub ad: u8,
     pub cd: u8,
-    pub rcode: u8,
+    pub rcode: RCode,
     pub qd_count: u16,
     pub an_count: u16,
     pub ns_count: u16,
     pub ar_count: u16,
 }
 
+/// Response codes, RFC 1035 4.1.1 and RFC 2136 2.2.
+#[derive(Debug, PartialEq, Clone, Copy, Hash, Eq)]
+pub enum RCode {
+    NoError,
+    FormErr,
+    ServFail,
+    NXDomain,
+    NotImp,
+    Refused,
+    Unknown(u8),
+}
+
+impl From<u8> for RCode {
+    fn from(v: u8) -> RCode {
+        match v {
+            0 => RCode::NoError,
+            1 => RCode::FormErr,
+            2 => RCode::ServFail,
+            3 => RCode::NXDomain,
+            4 => RCode::NotImp,
+            5 => RCode::Refused,
+            _ => RCode::Unknown(v),
+        }
+    }
+}
+
+impl From<RCode> for u8 {
+    fn from(r: RCode) -> u8 {
+        match r {
+            RCode::NoError => 0,
+            RCode::FormErr => 1,
+            RCode::ServFail => 2,
+            RCode::NXDomain => 3,
+            RCode::NotImp => 4,
+            RCode::Refused => 5,
+            RCode::Unknown(v) => v,
+        }
+    }
+}
+
 bitflags! {
     struct HeaderDataFlags: u16 {
         const QR =     0b1000000000000000;
@@ -54,12 +95,12 @@ impl Header {
         let (data, ns_count) = be_u16(data)?;
         let (data, ar_count) = be_u16(data)?;
 
-        return Ok((
+        Ok((
             data,
             Header {
-                id: id,
+                id,
                 qr: ((flag & HeaderDataFlags::QR) != 0) as u8,
-                opcode: (flag & HeaderDataFlags::OPCODE) as u8,
+                opcode: ((flag & HeaderDataFlags::OPCODE) >> 11) as u8,
                 aa: ((flag & HeaderDataFlags::AA) != 0) as u8,
                 tc: ((flag & HeaderDataFlags::TC) != 0) as u8,
                 rd: ((flag & HeaderDataFlags::RD) != 0) as u8,
@@ -67,13 +108,13 @@ impl Header {
                 z: ((flag & HeaderDataFlags::Z) != 0) as u8,
                 ad: ((flag & HeaderDataFlags::AD) != 0) as u8,
                 cd: ((flag & HeaderDataFlags::CD) != 0) as u8,
-                rcode: (flag & HeaderDataFlags::RCODE) as u8,
-                qd_count: qd_count,
-                an_count: an_count,
-                ns_count: ns_count,
-                ar_count: ar_count,
+                rcode: RCode::from((flag & HeaderDataFlags::RCODE) as u8),
+                qd_count,
+                an_count,
+                ns_count,
+                ar_count,
             },
-        ));
+        ))
     }
 
     pub async fn to_vec(&self) -> std::io::Result<Vec<u8>> {
@@ -90,7 +131,7 @@ impl Header {
         flag += (self.z as u16) << 6;
         flag += (self.ad as u16) << 5;
         flag += (self.cd as u16) << 4;
-        flag += self.rcode as u16;
+        flag += u8::from(self.rcode) as u16 & HeaderDataFlags::RCODE.bits;
         v.write_u16(flag).await?;
 
         v.write_u16(self.qd_count).await?;
@@ -98,13 +139,13 @@ impl Header {
         v.write_u16(self.ns_count).await?;
         v.write_u16(self.ar_count).await?;
 
-        return Ok(v);
+        Ok(v)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Header;
+    use super::{Header, RCode};
 
     #[tokio::test]
     async fn parse_header() {
@@ -126,7 +167,7 @@ mod tests {
                 z: 0,
                 ad: 1,
                 cd: 0,
-                rcode: 0,
+                rcode: RCode::NoError,
                 qd_count: 1,
                 an_count: 0,
                 ns_count: 0,
@@ -148,7 +189,7 @@ mod tests {
             z: 0,
             ad: 1,
             cd: 0,
-            rcode: 0,
+            rcode: RCode::NoError,
             qd_count: 1,
             an_count: 0,
             ns_count: 0,
@@ -158,4 +199,13 @@ mod tests {
         let result = h.to_vec().await.unwrap();
         assert_eq!(result, vec![196, 171, 1, 32, 0, 1, 0, 0, 0, 0, 0, 0]);
     }
+
+    #[tokio::test]
+    async fn parse_header_opcode_rcode() {
+        let data: Vec<u8> = vec![0, 1, 0x28, 0x85, 0, 0, 0, 0, 0, 0, 0, 0];
+        let (_, h) = Header::read(&data).unwrap();
+        assert_eq!(h.opcode, 5);
+        assert_eq!(h.rcode, RCode::Refused);
+        assert_eq!(h.to_vec().await.unwrap(), data);
+    }
 }
@@ -1,5 +1,7 @@
 pub mod compression;
 pub mod domain;
+pub mod edns;
+pub mod error;
 pub mod header;
 pub mod message;
 pub mod qtype;
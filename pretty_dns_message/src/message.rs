@@ -1,72 +1,123 @@
-use crate::{header, query, resource};
-use nom::{combinator::cond, multi::count, IResult};
+use crate::{
+    compression::Encoder,
+    edns,
+    error::{IResult, MessageError},
+    header,
+    qtype::QType,
+    query, resource,
+};
+use nom::combinator::cond;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Message {
     pub header: header::Header,
     pub query: Option<query::Query>,
     pub answer: Vec<resource::Resource>,
     pub authority: Vec<resource::Resource>,
     pub additional: Vec<resource::Resource>,
+    pub edns: Option<edns::Edns>,
 }
 
 impl Message {
-    pub fn from_bytes(data: &[u8]) -> IResult<&[u8], Message> {
-        let (data, h) = header::Header::read(data)?;
-        let (data, q) = cond(h.qd_count > 0, query::Query::read)(data)?;
-        let (data, a) = count(resource::Resource::read, h.an_count.into())(data)?;
-        let (data, au) = count(resource::Resource::read, h.ns_count.into())(data)?;
-        let (data, ad) = count(resource::Resource::read, h.ar_count.into())(data)?;
-
-        return Ok((
-            data,
-            Message {
-                header: h,
-                query: q,
-                answer: a,
-                authority: au,
-                additional: ad,
-            },
-        ));
+    pub fn from_bytes(message: &[u8]) -> Result<Message, MessageError> {
+        let (data, h) = header::Header::read(message)?;
+        // only one question is supported, like almost every implementation
+        if h.qd_count > 1 {
+            return Err(MessageError::CountMismatch);
+        }
+
+        let (data, q) = cond(h.qd_count > 0, query::Query::read(message))(data)?;
+        let (data, a) = Message::read_section(message, h.an_count)(data)?;
+        let (data, au) = Message::read_section(message, h.ns_count)(data)?;
+        let (data, mut ad) = Message::read_section(message, h.ar_count)(data)?;
+        if !data.is_empty() {
+            return Err(MessageError::TrailingBytes);
+        }
+
+        // the OPT pseudo record is not a part of the additional section
+        let mut edns = None;
+        if let Some(i) = ad.iter().position(|v| v._type == QType::OPT) {
+            edns = edns::Edns::from_resource(&ad.remove(i));
+        }
+
+        Ok(Message {
+            header: h,
+            query: q,
+            answer: a,
+            authority: au,
+            additional: ad,
+            edns,
+        })
     }
 
+    /// Read `count` records. Running out of input between records means the
+    /// header claims more records than the message has.
+    fn read_section<'a>(
+        message: &'a [u8],
+        count: u16,
+    ) -> impl FnMut(&'a [u8]) -> IResult<&'a [u8], Vec<resource::Resource>> {
+        move |mut data: &'a [u8]| {
+            let mut result = vec![];
+            for _ in 0..count {
+                if data.is_empty() {
+                    return Err(nom::Err::Failure(MessageError::CountMismatch));
+                }
+
+                let (_data, r) = resource::Resource::read(message)(data)?;
+                data = _data;
+                result.push(r);
+            }
+
+            Ok((data, result))
+        }
+    }
+
+    /// Serialize the message. The section counts in the header are derived
+    /// from the sections, including the OPT record for `edns`.
     pub async fn to_vec(&self) -> std::io::Result<Vec<u8>> {
-        let mut result = vec![];
+        let mut e = Encoder::new();
 
-        let h = self.header.to_vec().await?;
-        result.extend_from_slice(&h);
+        let mut h = self.header.clone();
+        h.qd_count = self.query.is_some() as u16;
+        h.an_count = self.answer.len() as u16;
+        h.ns_count = self.authority.len() as u16;
+        h.ar_count = (self.additional.len() + self.edns.is_some() as usize) as u16;
+        e.write_all(&h.to_vec().await?);
 
         if let Some(ref v) = self.query {
-            let q = v.to_vec().await?;
-            result.extend_from_slice(&q);
+            v.write(&mut e);
         }
 
         for v in &self.answer {
-            let a = v.to_vec().await?;
-            result.extend_from_slice(&a);
+            v.write(&mut e)?;
         }
 
         for v in &self.authority {
-            let a = v.to_vec().await?;
-            result.extend_from_slice(&a);
+            v.write(&mut e)?;
         }
 
         for v in &self.additional {
-            let a = v.to_vec().await?;
-            result.extend_from_slice(&a);
+            v.write(&mut e)?;
         }
 
-        return Ok(result);
+        if let Some(ref v) = self.edns {
+            v.to_resource().write(&mut e)?;
+        }
+
+        Ok(e.into_vec())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::Message;
-    use crate::compression::{CompressionData, CompressionType, DataType};
     use crate::domain::Domain;
+    use crate::edns::Edns;
+    use crate::error::MessageError;
+    use crate::header::RCode;
     use crate::qtype::QType;
-    use crate::resource::Resource;
+    use crate::resource::{RData, Resource};
+    use std::net::Ipv4Addr;
 
     #[tokio::test]
     async fn parse_message() {
@@ -74,7 +125,7 @@ mod tests {
             245, 212, 1, 32, 0, 1, 0, 0, 0, 0, 0, 0, 6, 103, 111, 111, 103, 108, 101, 3, 99, 111,
             109, 0, 0, 1, 0, 1,
         ];
-        let (_, result) = Message::from_bytes(&data).unwrap();
+        let result = Message::from_bytes(&data).unwrap();
 
         assert_eq!(
             result,
@@ -90,7 +141,7 @@ mod tests {
                     z: 0,
                     ad: 1,
                     cd: 0,
-                    rcode: 0,
+                    rcode: RCode::NoError,
                     qd_count: 1,
                     an_count: 0,
                     ns_count: 0,
@@ -104,6 +155,7 @@ mod tests {
                 answer: vec![],
                 authority: vec![],
                 additional: vec![],
+                edns: None,
             }
         );
     }
@@ -114,7 +166,7 @@ mod tests {
             226, 29, 1, 32, 0, 1, 0, 0, 0, 0, 0, 1, 6, 103, 111, 111, 103, 108, 101, 3, 99, 111,
             109, 0, 0, 1, 0, 1, 0, 0, 41, 16, 0, 0, 0, 0, 0, 0, 0,
         ];
-        let (_, result) = Message::from_bytes(&data).unwrap();
+        let result = Message::from_bytes(&data).unwrap();
 
         assert_eq!(
             result,
@@ -130,7 +182,7 @@ mod tests {
                     z: 0,
                     ad: 1,
                     cd: 0,
-                    rcode: 0,
+                    rcode: RCode::NoError,
                     qd_count: 1,
                     an_count: 0,
                     ns_count: 0,
@@ -143,15 +195,162 @@ mod tests {
                 }),
                 answer: vec![],
                 authority: vec![],
-                additional: vec![Resource {
-                    name: CompressionData::new(vec![], CompressionType::Domain),
-                    _type: QType::Unknown(41),
-                    class: 4096,
-                    ttl: 0,
-                    rdlength: 0,
-                    rdata: CompressionData::new(vec![], CompressionType::Data),
-                }],
+                additional: vec![],
+                edns: Some(Edns::new(4096)),
             }
         );
+
+        assert_eq!(result.to_vec().await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn write_message_compression() {
+        let google = Domain::from(b"google.com.".to_vec());
+        let message = super::Message {
+            header: super::header::Header {
+                id: 62932,
+                qr: 1,
+                opcode: 0,
+                aa: 0,
+                tc: 0,
+                rd: 1,
+                ra: 1,
+                z: 0,
+                ad: 0,
+                cd: 0,
+                rcode: RCode::NoError,
+                qd_count: 1,
+                an_count: 1,
+                ns_count: 1,
+                ar_count: 0,
+            },
+            query: Some(super::query::Query {
+                qname: google.clone(),
+                qclass: 1,
+                qtype: QType::A,
+            }),
+            answer: vec![Resource {
+                name: google.clone(),
+                _type: QType::A,
+                class: 1,
+                ttl: 299,
+                rdata: RData::A(Ipv4Addr::new(172, 217, 25, 238)),
+            }],
+            authority: vec![Resource {
+                name: Domain::from("com."),
+                _type: QType::NS,
+                class: 1,
+                ttl: 299,
+                rdata: RData::NS(Domain::from("ns1.google.com.")),
+            }],
+            additional: vec![],
+            edns: None,
+        };
+
+        let result = message.to_vec().await.unwrap();
+        assert_eq!(
+            result,
+            vec![
+                245, 212, 129, 128, 0, 1, 0, 1, 0, 1, 0, 0, 6, 103, 111, 111, 103, 108, 101, 3, 99,
+                111, 109, 0, 0, 1, 0, 1, 192, 12, 0, 1, 0, 1, 0, 0, 1, 43, 0, 4, 172, 217, 25, 238,
+                192, 19, 0, 2, 0, 1, 0, 0, 1, 43, 0, 6, 3, 110, 115, 49, 192, 12
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn parse_message_compression() {
+        let data = vec![
+            245, 212, 129, 128, 0, 1, 0, 1, 0, 1, 0, 0, 6, 103, 111, 111, 103, 108, 101, 3, 99,
+            111, 109, 0, 0, 1, 0, 1, 192, 12, 0, 1, 0, 1, 0, 0, 1, 43, 0, 4, 172, 217, 25, 238,
+            192, 19, 0, 2, 0, 1, 0, 0, 1, 43, 0, 6, 3, 110, 115, 49, 192, 12,
+        ];
+        let result = Message::from_bytes(&data).unwrap();
+
+        assert_eq!(result.answer[0].name, Domain::from("google.com."));
+        assert_eq!(result.authority[0].name, Domain::from("com."));
+        assert_eq!(
+            result.authority[0].rdata,
+            RData::NS(Domain::from("ns1.google.com."))
+        );
+    }
+
+    #[tokio::test]
+    async fn message_larger_than_256_bytes() {
+        let mut authority = vec![];
+        let mut additional = vec![];
+        for i in 0..13 {
+            let ns = Domain::from(format!("{}.gtld-servers.net.", (b'a' + i) as char));
+            authority.push(Resource {
+                name: Domain::from("com."),
+                _type: QType::NS,
+                class: 1,
+                ttl: 172800,
+                rdata: RData::NS(ns.clone()),
+            });
+            additional.push(Resource {
+                name: ns,
+                _type: QType::A,
+                class: 1,
+                ttl: 172800,
+                rdata: RData::A(Ipv4Addr::new(192, 5, 6, 30 + i)),
+            });
+        }
+
+        let message = super::Message {
+            header: super::header::Header {
+                id: 1,
+                qr: 1,
+                opcode: 0,
+                aa: 0,
+                tc: 0,
+                rd: 0,
+                ra: 0,
+                z: 0,
+                ad: 0,
+                cd: 0,
+                rcode: RCode::NoError,
+                qd_count: 1,
+                an_count: 0,
+                ns_count: 13,
+                ar_count: 13,
+            },
+            query: Some(super::query::Query {
+                qname: Domain::from("www.google.com."),
+                qclass: 1,
+                qtype: QType::A,
+            }),
+            answer: vec![],
+            authority,
+            additional,
+            edns: None,
+        };
+
+        let data = message.to_vec().await.unwrap();
+        assert!(data.len() > 256);
+
+        let result = Message::from_bytes(&data).unwrap();
+        assert_eq!(result, message);
+    }
+
+    #[tokio::test]
+    async fn parse_message_errors() {
+        let data = vec![245, 212, 1, 32, 0, 1];
+        assert_eq!(Message::from_bytes(&data), Err(MessageError::Truncated));
+
+        let data = vec![
+            245, 212, 1, 32, 0, 1, 0, 1, 0, 0, 0, 0, 6, 103, 111, 111, 103, 108, 101, 3, 99, 111,
+            109, 0, 0, 1, 0, 1,
+        ];
+        assert_eq!(Message::from_bytes(&data), Err(MessageError::CountMismatch));
+
+        let data = vec![
+            245, 212, 1, 32, 0, 1, 0, 0, 0, 0, 0, 0, 6, 103, 111, 111, 103, 108, 101, 3, 99, 111,
+            109, 0, 0, 1, 0, 1, 0,
+        ];
+        assert_eq!(Message::from_bytes(&data), Err(MessageError::TrailingBytes));
+
+        let data = vec![245, 212, 1, 32, 0, 1, 0, 0, 0, 0, 0, 0, 192, 12, 0, 1, 0, 1];
+        assert_eq!(Message::from_bytes(&data), Err(MessageError::PointerLoop));
     }
 }
@@ -1,8 +1,17 @@
 #[derive(Debug, PartialEq, Clone, Copy, Hash, Eq)]
 pub enum QType {
     A,
-    AAAA,
     NS,
+    CNAME,
+    SOA,
+    PTR,
+    MX,
+    TXT,
+    AAAA,
+    SRV,
+    DNAME,
+    OPT,
+    CAA,
     Unknown(u16),
 }
 
@@ -11,18 +20,36 @@ impl From<u16> for QType {
         match v {
             1 => QType::A,
             2 => QType::NS,
+            5 => QType::CNAME,
+            6 => QType::SOA,
+            12 => QType::PTR,
+            15 => QType::MX,
+            16 => QType::TXT,
             28 => QType::AAAA,
+            33 => QType::SRV,
+            39 => QType::DNAME,
+            41 => QType::OPT,
+            257 => QType::CAA,
             _ => QType::Unknown(v),
         }
     }
 }
 
-impl Into<u16> for QType {
-    fn into(self) -> u16 {
-        match self {
+impl From<QType> for u16 {
+    fn from(q: QType) -> u16 {
+        match q {
             QType::A => 1,
             QType::NS => 2,
+            QType::CNAME => 5,
+            QType::SOA => 6,
+            QType::PTR => 12,
+            QType::MX => 15,
+            QType::TXT => 16,
             QType::AAAA => 28,
+            QType::SRV => 33,
+            QType::DNAME => 39,
+            QType::OPT => 41,
+            QType::CAA => 257,
             QType::Unknown(v) => v,
         }
     }
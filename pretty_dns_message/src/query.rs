@@ -1,8 +1,8 @@
-use crate::{domain::Domain, qtype::QType};
-use nom::{combinator::map, number::complete::be_u16, IResult};
+use crate::{compression::Encoder, domain::Domain, error::IResult, qtype::QType};
+use nom::{combinator::map, number::complete::be_u16};
 use tokio::io::AsyncWriteExt;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Query {
     pub qname: Domain,
     pub qtype: QType,
@@ -10,19 +10,21 @@ pub struct Query {
 }
 
 impl Query {
-    pub fn read(data: &[u8]) -> IResult<&[u8], Query> {
-        let (data, qname) = Domain::read(data)?;
-        let (data, qtype) = map(be_u16, |q| q.into())(data)?;
-        let (data, qclass) = be_u16(data)?;
+    pub fn read<'a>(message: &'a [u8]) -> impl FnMut(&'a [u8]) -> IResult<&'a [u8], Query> {
+        move |data: &'a [u8]| {
+            let (data, qname) = Domain::read(message)(data)?;
+            let (data, qtype) = map(be_u16, |q| q.into())(data)?;
+            let (data, qclass) = be_u16(data)?;
 
-        return Ok((
-            data,
-            Query {
-                qname: qname,
-                qtype: qtype,
-                qclass: qclass,
-            },
-        ));
+            Ok((
+                data,
+                Query {
+                    qname,
+                    qtype,
+                    qclass,
+                },
+            ))
+        }
     }
 
     pub async fn to_vec(&self) -> std::io::Result<Vec<u8>> {
@@ -32,7 +34,13 @@ impl Query {
         v.write_u16(self.qtype.into()).await?;
         v.write_u16(self.qclass).await?;
 
-        return Ok(v);
+        Ok(v)
+    }
+
+    pub fn write(&self, e: &mut Encoder) {
+        e.write_name(&self.qname, true);
+        e.write_u16(self.qtype.into());
+        e.write_u16(self.qclass);
     }
 }
 
@@ -47,7 +55,7 @@ mod tests {
         let data: Vec<u8> = vec![
             6, 103, 111, 111, 103, 108, 101, 3, 99, 111, 109, 0, 0, 1, 0, 1,
         ];
-        let (_, q) = Query::read(&data).unwrap();
+        let (_, q) = Query::read(&data)(&data).unwrap();
         assert_eq!(
             q,
             Query {
@@ -63,7 +71,7 @@ mod tests {
         let q = Query {
             qname: Domain::from(b"google.com.".to_vec()),
             qclass: 1,
-            qtype: QType::A.into(),
+            qtype: QType::A,
         };
 
         let result = q.to_vec().await.unwrap();
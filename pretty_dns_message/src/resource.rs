@@ -1,130 +1,395 @@
 use crate::{
-    compression::{CompressionData, CompressionType, DataType},
+    compression::Encoder,
+    domain::Domain,
+    error::{IResult, MessageError},
     qtype::QType,
 };
 use nom::{
-    combinator::map,
-    multi::count,
-    number::complete::{be_u16, be_u32, be_u8},
-    IResult,
+    bytes::complete::take,
+    combinator::{all_consuming, map, rest},
+    multi::{length_data, many0},
+    number::complete::{be_u128, be_u16, be_u32, be_u8},
+};
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr},
 };
-use tokio::io::AsyncWriteExt;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Resource {
-    pub name: CompressionData,
+    pub name: Domain,
     pub _type: QType,
     pub class: u16,
     pub ttl: u32,
-    pub rdlength: u16,
-    pub rdata: CompressionData,
+    pub rdata: RData,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum RData {
+    A(Ipv4Addr),
+    AAAA(Ipv6Addr),
+    NS(Domain),
+    CNAME(Domain),
+    SOA {
+        mname: Domain,
+        rname: Domain,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+    MX {
+        preference: u16,
+        exchange: Domain,
+    },
+    TXT(Vec<Vec<u8>>),
+    PTR(Domain),
+    SRV {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: Domain,
+    },
+    DNAME(Domain),
+    CAA {
+        flags: u8,
+        tag: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Unknown(Vec<u8>),
 }
 
 impl Resource {
-    pub fn read(data: &[u8]) -> IResult<&[u8], Resource> {
-        let (data, name) = CompressionData::from_domain(data)?;
-        let (data, _type) = map(be_u16, |q| q.into())(data)?;
-        let (data, class) = be_u16(data)?;
-        let (data, ttl) = be_u32(data)?;
-        let (data, rdlength) = be_u16(data)?;
-
-        let (data, rdata) = if _type == QType::NS {
-            CompressionData::from_domain(data)?
-        } else {
-            let (data, rdata) = count(be_u8, rdlength.into())(data)?;
-            (
+    pub fn read<'a>(message: &'a [u8]) -> impl FnMut(&'a [u8]) -> IResult<&'a [u8], Resource> {
+        move |data: &'a [u8]| {
+            let (data, name) = Domain::read(message)(data)?;
+            let (data, _type) = map(be_u16, |q| q.into())(data)?;
+            let (data, class) = be_u16(data)?;
+            let (data, ttl) = be_u32(data)?;
+            let (data, rdlength) = be_u16(data)?;
+            let (data, rdata) = take(rdlength)(data)?;
+            let (_, rdata) =
+                all_consuming(RData::read(message, _type))(rdata).map_err(|e| match e {
+                    nom::Err::Error(MessageError::Truncated)
+                    | nom::Err::Failure(MessageError::Truncated) => {
+                        nom::Err::Failure(MessageError::BadRdata)
+                    }
+                    e => e,
+                })?;
+
+            Ok((
                 data,
-                CompressionData::new(
-                    if rdlength > 0 {
-                        vec![DataType::Raw(rdata.to_vec())]
-                    } else {
-                        vec![]
+                Resource {
+                    name,
+                    _type,
+                    class,
+                    ttl,
+                    rdata,
+                },
+            ))
+        }
+    }
+
+    pub async fn to_vec(&self) -> std::io::Result<Vec<u8>> {
+        let mut e = Encoder::without_compression();
+        self.write(&mut e)?;
+
+        Ok(e.into_vec())
+    }
+
+    pub fn write(&self, e: &mut Encoder) -> io::Result<()> {
+        e.write_name(&self.name, true);
+        e.write_u16(self._type.into());
+        e.write_u16(self.class);
+        e.write_u32(self.ttl);
+
+        let rdlength = e.len();
+        e.write_u16(0);
+        self.rdata.write(e)?;
+        let length = u16::try_from(e.len() - rdlength - 2)
+            .map_err(|_| invalid_input("rdata is longer than 65535 bytes"))?;
+        e.set_u16(rdlength, length);
+
+        Ok(())
+    }
+}
+
+impl RData {
+    pub fn read<'a>(
+        message: &'a [u8],
+        _type: QType,
+    ) -> impl FnMut(&'a [u8]) -> IResult<&'a [u8], RData> {
+        move |data: &'a [u8]| match _type {
+            QType::A => map(be_u32, |v| RData::A(Ipv4Addr::from(v)))(data),
+            QType::AAAA => map(be_u128, |v| RData::AAAA(Ipv6Addr::from(v)))(data),
+            QType::NS => map(Domain::read(message), RData::NS)(data),
+            QType::CNAME => map(Domain::read(message), RData::CNAME)(data),
+            QType::PTR => map(Domain::read(message), RData::PTR)(data),
+            QType::DNAME => map(Domain::read(message), RData::DNAME)(data),
+            QType::SOA => {
+                let (data, mname) = Domain::read(message)(data)?;
+                let (data, rname) = Domain::read(message)(data)?;
+                let (data, serial) = be_u32(data)?;
+                let (data, refresh) = be_u32(data)?;
+                let (data, retry) = be_u32(data)?;
+                let (data, expire) = be_u32(data)?;
+                let (data, minimum) = be_u32(data)?;
+
+                Ok((
+                    data,
+                    RData::SOA {
+                        mname,
+                        rname,
+                        serial,
+                        refresh,
+                        retry,
+                        expire,
+                        minimum,
                     },
-                    CompressionType::Data,
-                ),
-            )
-        };
+                ))
+            }
+            QType::MX => {
+                let (data, preference) = be_u16(data)?;
+                let (data, exchange) = Domain::read(message)(data)?;
 
-        return Ok((
-            data,
-            Resource {
-                name: name,
-                _type: _type,
-                class: class,
-                ttl: ttl,
-                rdlength: rdlength,
-                rdata: rdata,
-            },
-        ));
+                Ok((
+                    data,
+                    RData::MX {
+                        preference,
+                        exchange,
+                    },
+                ))
+            }
+            QType::TXT => map(many0(length_data(be_u8)), |v: Vec<&[u8]>| {
+                RData::TXT(v.into_iter().map(|s| s.to_vec()).collect())
+            })(data),
+            QType::SRV => {
+                let (data, priority) = be_u16(data)?;
+                let (data, weight) = be_u16(data)?;
+                let (data, port) = be_u16(data)?;
+                let (data, target) = Domain::read(message)(data)?;
+
+                Ok((
+                    data,
+                    RData::SRV {
+                        priority,
+                        weight,
+                        port,
+                        target,
+                    },
+                ))
+            }
+            QType::CAA => {
+                let (data, flags) = be_u8(data)?;
+                let (data, tag) = length_data(be_u8)(data)?;
+                let (data, value) = rest(data)?;
+
+                Ok((
+                    data,
+                    RData::CAA {
+                        flags,
+                        tag: tag.to_vec(),
+                        value: value.to_vec(),
+                    },
+                ))
+            }
+            QType::OPT | QType::Unknown(_) => {
+                map(rest, |v: &[u8]| RData::Unknown(v.to_vec()))(data)
+            }
+        }
     }
 
     pub async fn to_vec(&self) -> std::io::Result<Vec<u8>> {
-        let mut v = vec![];
+        let mut e = Encoder::without_compression();
+        self.write(&mut e)?;
+
+        Ok(e.into_vec())
+    }
 
-        let name: Vec<u8> = self.name.clone().into();
+    /// Write RDATA. Only names in the types listed in RFC 3597 section 4
+    /// may be compressed, other names are written in full. Values which do
+    /// not fit their length fields are rejected rather than cut.
+    pub fn write(&self, e: &mut Encoder) -> io::Result<()> {
+        match self {
+            RData::A(addr) => e.write_all(&addr.octets()),
+            RData::AAAA(addr) => e.write_all(&addr.octets()),
+            RData::NS(name) | RData::CNAME(name) | RData::PTR(name) => e.write_name(name, true),
+            // RFC 6672 2.5
+            RData::DNAME(target) => e.write_name(target, false),
+            RData::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                e.write_name(mname, true);
+                e.write_name(rname, true);
+                e.write_u32(*serial);
+                e.write_u32(*refresh);
+                e.write_u32(*retry);
+                e.write_u32(*expire);
+                e.write_u32(*minimum);
+            }
+            RData::MX {
+                preference,
+                exchange,
+            } => {
+                e.write_u16(*preference);
+                e.write_name(exchange, true);
+            }
+            RData::TXT(strings) => {
+                // RFC 1035 3.3.14
+                if strings.is_empty() {
+                    return Err(invalid_input("txt has no character-string"));
+                }
+                for s in strings {
+                    write_character_string(e, s)?;
+                }
+            }
+            RData::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                e.write_u16(*priority);
+                e.write_u16(*weight);
+                e.write_u16(*port);
+                e.write_name(target, false);
+            }
+            RData::CAA { flags, tag, value } => {
+                // RFC 8659 4.1
+                if tag.is_empty() {
+                    return Err(invalid_input("caa tag is empty"));
+                }
+                e.write_u8(*flags);
+                write_character_string(e, tag)?;
+                e.write_all(value);
+            }
+            RData::Unknown(data) => e.write_all(data),
+        }
 
-        v.write_all(&name).await?;
+        Ok(())
+    }
+}
 
-        v.write_u16(self._type.into()).await?;
-        v.write_u16(self.class).await?;
-        v.write_u32(self.ttl).await?;
-        v.write_u16(self.rdlength).await?;
+fn write_character_string(e: &mut Encoder, s: &[u8]) -> io::Result<()> {
+    let length = u8::try_from(s.len())
+        .map_err(|_| invalid_input("character-string is longer than 255 bytes"))?;
+    e.write_u8(length);
+    e.write_all(s);
 
-        let rdata: Vec<u8> = self.rdata.clone().into();
-        v.write_all(&rdata).await?;
+    Ok(())
+}
 
-        return Ok(v);
-    }
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
 }
 
 #[cfg(test)]
 mod tests {
     use super::QType;
-    use super::Resource;
-    use crate::compression::{CompressionData, CompressionType, DataType};
+    use super::{RData, Resource};
+    use crate::{domain::Domain, error::MessageError};
+    use std::net::Ipv4Addr;
+
+    // google.com. at offset 0, which the records in the tests point to.
+    const GOOGLE: [u8; 12] = [6, 103, 111, 111, 103, 108, 101, 3, 99, 111, 109, 0];
+
+    fn message(record: &[u8]) -> Vec<u8> {
+        let mut message = GOOGLE.to_vec();
+        message.extend_from_slice(record);
+        message
+    }
 
     #[tokio::test]
     async fn parse_resource() {
-        let data: Vec<u8> = vec![192, 12, 0, 1, 0, 1, 0, 0, 1, 43, 0, 4, 172, 217, 25, 238];
-        let (_, q) = Resource::read(&data).unwrap();
+        let data = message(&[192, 0, 0, 1, 0, 1, 0, 0, 1, 43, 0, 4, 172, 217, 25, 238]);
+        let (_, q) = Resource::read(&data)(&data[12..]).unwrap();
 
         assert_eq!(
             q,
             Resource {
-                name: CompressionData::new(
-                    vec![DataType::Compression { position: 12 }],
-                    CompressionType::Domain
-                ),
+                name: Domain::from("google.com."),
                 _type: QType::A,
                 class: 1,
                 ttl: 299,
-                rdlength: 4,
-                rdata: CompressionData::new(
-                    vec![DataType::Raw(vec![172, 217, 25, 238]),],
-                    CompressionType::Data,
-                ),
+                rdata: RData::A(Ipv4Addr::new(172, 217, 25, 238)),
             }
         );
     }
 
+    #[tokio::test]
+    async fn parse_resource_mx() {
+        let data = message(&[
+            192, 0, 0, 15, 0, 1, 0, 0, 1, 43, 0, 9, 0, 10, 4, 115, 109, 116, 112, 192, 0,
+        ]);
+        let (_, q) = Resource::read(&data)(&data[12..]).unwrap();
+
+        assert_eq!(
+            q.rdata,
+            RData::MX {
+                preference: 10,
+                exchange: Domain::from("smtp.google.com."),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn parse_resource_txt() {
+        let data = message(&[
+            192, 0, 0, 16, 0, 1, 0, 0, 1, 43, 0, 7, 2, 104, 105, 3, 102, 111, 111,
+        ]);
+        let (_, q) = Resource::read(&data)(&data[12..]).unwrap();
+
+        assert_eq!(q.rdata, RData::TXT(vec![b"hi".to_vec(), b"foo".to_vec()]));
+    }
+
+    #[tokio::test]
+    async fn parse_resource_wrong_rdlength() {
+        let data = message(&[192, 0, 0, 1, 0, 1, 0, 0, 1, 43, 0, 3, 172, 217, 25]);
+        assert_eq!(
+            Resource::read(&data)(&data[12..]),
+            Err(nom::Err::Failure(MessageError::BadRdata))
+        );
+
+        let data = message(&[192, 0, 0, 1, 0, 1, 0, 0, 1, 43, 0, 5, 172, 217, 25, 238, 1]);
+        assert_eq!(
+            Resource::read(&data)(&data[12..]),
+            Err(nom::Err::Failure(MessageError::BadRdata))
+        );
+    }
+
+    #[tokio::test]
+    async fn parse_resource_truncated() {
+        let data = message(&[192, 0, 0, 1, 0, 1, 0, 0, 1, 43, 0, 4, 172, 217]);
+        assert_eq!(
+            Resource::read(&data)(&data[12..]),
+            Err(nom::Err::Error(MessageError::Truncated))
+        );
+    }
+
+    #[tokio::test]
+    async fn parse_resource_unknown() {
+        let data = message(&[192, 0, 0, 99, 0, 1, 0, 0, 1, 43, 0, 2, 1, 2]);
+        let (_, q) = Resource::read(&data)(&data[12..]).unwrap();
+
+        assert_eq!(q._type, QType::Unknown(99));
+        assert_eq!(q.rdata, RData::Unknown(vec![1, 2]));
+    }
+
     #[tokio::test]
     async fn write_resource() {
         let h = Resource {
-            name: CompressionData::new(
-                vec![
-                    DataType::Raw(vec![103, 111, 111, 103, 108, 101]),
-                    DataType::Raw(vec![99, 111, 109]),
-                ],
-                CompressionType::Domain,
-            ),
+            name: Domain::from("google.com."),
             _type: QType::A,
             class: 1,
             ttl: 299,
-            rdlength: 4,
-            rdata: CompressionData::new(
-                vec![DataType::Raw(vec![172, 217, 25, 238])],
-                CompressionType::Data,
-            ),
+            rdata: RData::A(Ipv4Addr::new(172, 217, 25, 238)),
         };
 
         let result = h.to_vec().await.unwrap();
@@ -136,4 +401,72 @@ mod tests {
             ]
         );
     }
+
+    #[tokio::test]
+    async fn write_resource_soa() {
+        let h = Resource {
+            name: Domain::from("com."),
+            _type: QType::SOA,
+            class: 1,
+            ttl: 60,
+            rdata: RData::SOA {
+                mname: Domain::from("ns.com."),
+                rname: Domain::from("com."),
+                serial: 1,
+                refresh: 2,
+                retry: 3,
+                expire: 4,
+                minimum: 5,
+            },
+        };
+
+        let result = h.to_vec().await.unwrap();
+        assert_eq!(
+            result,
+            vec![
+                3, 99, 111, 109, 0, 0, 6, 0, 1, 0, 0, 0, 60, 0, 33, 2, 110, 115, 3, 99, 111, 109,
+                0, 3, 99, 111, 109, 0, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0, 5
+            ]
+        );
+
+        let (_, parsed) = Resource::read(&result)(&result).unwrap();
+        assert_eq!(parsed, h);
+    }
+
+    #[tokio::test]
+    async fn write_rdata_too_long() {
+        let err = |rdata: RData| async move {
+            let e = rdata.to_vec().await.unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+        };
+
+        // a string with 255 bytes is the longest one
+        let data = RData::TXT(vec![vec![b'a'; 255]]).to_vec().await.unwrap();
+        assert_eq!(data.len(), 256);
+        assert_eq!(data[0], 255);
+
+        err(RData::TXT(vec![vec![b'a'; 256]])).await;
+        err(RData::TXT(vec![])).await;
+        err(RData::CAA {
+            flags: 0,
+            tag: vec![b'a'; 256],
+            value: vec![],
+        })
+        .await;
+        err(RData::CAA {
+            flags: 0,
+            tag: vec![],
+            value: vec![],
+        })
+        .await;
+
+        let h = Resource {
+            name: Domain::from("com."),
+            _type: QType::Unknown(65280),
+            class: 1,
+            ttl: 60,
+            rdata: RData::Unknown(vec![0; 65536]),
+        };
+        assert!(h.to_vec().await.is_err());
+    }
 }
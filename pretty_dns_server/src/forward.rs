@@ -0,0 +1,409 @@
+use crate::server::Config;
+use once_cell::sync::Lazy;
+use pretty_dns_client::client;
+use pretty_dns_message::{
+    domain::Domain,
+    header::{Header, RCode},
+    message::Message,
+    qtype::QType,
+    query::Query,
+};
+use std::{
+    collections::HashSet,
+    io,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    str::FromStr,
+    sync::Mutex,
+};
+use tokio::time::{self, Duration};
+use tracing::{debug, warn};
+
+/// Upstreams which failed to answer, until a health check succeeds.
+static DOWN: Lazy<Mutex<HashSet<SocketAddr>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A server which queries are forwarded to, written as
+/// `ADDRESS[:PORT][/TIMEOUT_MS]`, for example `8.8.8.8`, `[2001:4860:4860::8888]:53`
+/// or `192.0.2.53:53/1500`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Upstream {
+    pub addr: SocketAddr,
+    pub timeout: Duration,
+}
+
+impl FromStr for Upstream {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Upstream, String> {
+        let (addr, timeout) = match s.rsplit_once('/') {
+            Some((addr, timeout)) => {
+                let timeout = timeout
+                    .parse()
+                    .map_err(|_| format!("invalid upstream timeout: {}", s))?;
+                (addr, Duration::from_millis(timeout))
+            }
+            None => (s, DEFAULT_TIMEOUT),
+        };
+
+        let addr = match addr.parse::<SocketAddr>() {
+            Ok(addr) => addr,
+            Err(_) => match addr.parse::<IpAddr>() {
+                Ok(addr) => SocketAddr::new(addr, 53),
+                Err(_) => return Err(format!("invalid upstream address: {}", s)),
+            },
+        };
+
+        Ok(Upstream { addr, timeout })
+    }
+}
+
+/// Queries for names in `zone` are forwarded to `upstreams`, written as
+/// `ZONE=UPSTREAM[,UPSTREAM...]`, for example `corp.example.=192.0.2.53,192.0.2.54/1000`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub zone: Domain,
+    pub upstreams: Vec<Upstream>,
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Rule, String> {
+        let (zone, upstreams) = s
+            .split_once('=')
+            .ok_or_else(|| format!("invalid forwarding rule: {}", s))?;
+
+        Rule::new(zone, upstreams.split(','))
+    }
+}
+
+impl Rule {
+    fn new<'a>(zone: &str, upstreams: impl Iterator<Item = &'a str>) -> Result<Rule, String> {
+        let upstreams = upstreams
+            .map(|v| v.trim().parse())
+            .collect::<Result<Vec<Upstream>, String>>()?;
+        if zone.is_empty() || upstreams.is_empty() {
+            return Err(format!("invalid forwarding rule: {}", zone));
+        }
+
+        Ok(Rule {
+            zone: Domain::from(zone),
+            upstreams,
+        })
+    }
+}
+
+/// Read forwarding rules from a file. Each line is either `ZONE UPSTREAM...`
+/// or a single `UPSTREAM` for every query. Empty lines and text after `#`
+/// are ignored.
+pub fn load(path: &Path) -> io::Result<Vec<Rule>> {
+    parse(&std::fs::read_to_string(path)?)
+}
+
+fn parse(data: &str) -> io::Result<Vec<Rule>> {
+    let mut rules = vec![];
+    for line in data.lines() {
+        let fields: Vec<&str> = line
+            .split('#')
+            .next()
+            .unwrap_or_default()
+            .split_whitespace()
+            .collect();
+
+        let rule = match fields[..] {
+            [] => continue,
+            [upstream] => Rule::new(".", [upstream].into_iter()),
+            [zone, ref upstreams @ ..] => Rule::new(zone, upstreams.iter().copied()),
+        };
+        rules.push(rule.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?);
+    }
+
+    Ok(merge(rules))
+}
+
+/// Merge the rules for the same zone, keeping the order of the upstreams.
+pub fn merge(rules: Vec<Rule>) -> Vec<Rule> {
+    let mut merged: Vec<Rule> = vec![];
+    for rule in rules {
+        match merged
+            .iter_mut()
+            .find(|v| v.zone.eq_ignore_case(&rule.zone))
+        {
+            Some(v) => v.upstreams.extend(rule.upstreams),
+            None => merged.push(rule),
+        }
+    }
+
+    merged
+}
+
+/// Find the rule for the longest zone which contains `qname`.
+pub fn find<'a>(rules: &'a [Rule], qname: &Domain) -> Option<&'a Rule> {
+    rules
+        .iter()
+        .filter(|v| qname.is_subdomain_of(&v.zone))
+        .max_by_key(|v| v.zone.labels().len())
+}
+
+/// Forward the request to the upstreams in the configured order until one
+/// of them answers. Upstreams which are down are tried last, and an upstream
+/// which does not answer is marked down. SERVFAIL and REFUSED are passed on
+/// to the next upstream without marking it down, since they may be caused
+/// by the name rather than the upstream.
+pub async fn forward(req: &Message, upstreams: &[Upstream], c: &Config) -> io::Result<Message> {
+    let mut upstreams = upstreams.to_vec();
+    upstreams.sort_by_key(|v| is_down(v.addr));
+
+    let mut last_error = io::Error::new(io::ErrorKind::Other, "no upstream");
+    for upstream in upstreams {
+        debug!("forward query to {:?}", upstream.addr);
+        let client_config = client::Config {
+            timeout: upstream.timeout,
+            ..c.client()
+        };
+
+        match client::forward(req.clone(), upstream.addr, &client_config).await {
+            Ok(res) if matches!(res.header.rcode, RCode::ServFail | RCode::Refused) => {
+                warn!(
+                    "upstream answered {:?}: {:?}",
+                    res.header.rcode, upstream.addr
+                );
+                last_error = io::Error::new(io::ErrorKind::Other, "upstream error");
+            }
+            Ok(res) => {
+                set_down(upstream.addr, false);
+                return Ok(res);
+            }
+            Err(e) => {
+                warn!("upstream error: {:?} {:?}", upstream.addr, e);
+                set_down(upstream.addr, true);
+                last_error = e;
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Forward a query made by the resolver itself, such as the target of a
+/// CNAME, with recursion desired.
+pub async fn forward_query(
+    query: Query,
+    upstreams: &[Upstream],
+    c: &Config,
+) -> io::Result<Message> {
+    let req = Message {
+        header: Header {
+            id: 0,
+            qr: 0,
+            opcode: 0,
+            aa: 0,
+            tc: 0,
+            rd: 1,
+            ra: 0,
+            z: 0,
+            ad: 0,
+            cd: 0,
+            rcode: RCode::NoError,
+            qd_count: 1,
+            an_count: 0,
+            ns_count: 0,
+            ar_count: 0,
+        },
+        query: Some(query),
+        answer: vec![],
+        authority: vec![],
+        additional: vec![],
+        edns: None,
+    };
+
+    forward(&req, upstreams, c).await
+}
+
+/// Query every upstream for the root NS records periodically, and mark the
+/// upstreams down or up by whether they answer.
+pub async fn health_check(upstreams: Vec<Upstream>, c: client::Config) {
+    let mut interval = time::interval(HEALTH_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        for upstream in &upstreams {
+            let q = Query {
+                qname: Domain::from("."),
+                qtype: QType::NS,
+                qclass: 1,
+            };
+            let client_config = client::Config {
+                timeout: upstream.timeout,
+                ..c.clone()
+            };
+
+            let healthy = match client::resolve(q, upstream.addr, &client_config).await {
+                Ok(res) => res.header.rcode != RCode::ServFail,
+                Err(_) => false,
+            };
+            if healthy == is_down(upstream.addr) {
+                debug!("upstream health changed: {:?} {}", upstream.addr, healthy);
+            }
+            set_down(upstream.addr, !healthy);
+        }
+    }
+}
+
+fn is_down(addr: SocketAddr) -> bool {
+    DOWN.lock().unwrap().contains(&addr)
+}
+
+fn set_down(addr: SocketAddr, down: bool) {
+    let mut v = DOWN.lock().unwrap();
+    if down {
+        v.insert(addr);
+    } else {
+        v.remove(&addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find, forward, is_down, parse, Rule, Upstream};
+    use crate::server::Config;
+    use pretty_dns_message::{
+        domain::Domain,
+        header::{Header, RCode},
+        message::Message,
+        qtype::QType,
+        query::Query,
+    };
+    use tokio::{net::UdpSocket, time::Duration};
+
+    #[test]
+    fn test_parse_upstream() {
+        assert_eq!(
+            "8.8.8.8".parse(),
+            Ok(Upstream {
+                addr: "8.8.8.8:53".parse().unwrap(),
+                timeout: Duration::from_secs(3),
+            })
+        );
+        assert_eq!(
+            "[2001:db8::53]:5353/1500".parse(),
+            Ok(Upstream {
+                addr: "[2001:db8::53]:5353".parse().unwrap(),
+                timeout: Duration::from_millis(1500),
+            })
+        );
+        assert!("8.8.8.8/fast".parse::<Upstream>().is_err());
+        assert!("dns.google".parse::<Upstream>().is_err());
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        let rule: Rule = "corp.example.=192.0.2.53, 192.0.2.54/1000".parse().unwrap();
+        assert_eq!(rule.zone, Domain::from("corp.example."));
+        assert_eq!(rule.upstreams.len(), 2);
+        assert!("corp.example.".parse::<Rule>().is_err());
+        assert!("corp.example.=".parse::<Rule>().is_err());
+
+        let rules = parse(
+            "# forwarders
+192.0.2.1
+corp.example. 192.0.2.53 192.0.2.54:5353/100 # internal
+
+192.0.2.2
+",
+        )
+        .unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].zone, Domain::from("."));
+        assert_eq!(rules[0].upstreams.len(), 2);
+        assert_eq!(rules[1].upstreams.len(), 2);
+        assert!(parse("192.0.2.1\nbroken\n").is_err());
+    }
+
+    #[test]
+    fn test_find() {
+        let rules: Vec<Rule> = vec![
+            ".=192.0.2.1".parse().unwrap(),
+            "example.=192.0.2.2".parse().unwrap(),
+            "corp.example.=192.0.2.3".parse().unwrap(),
+            "10.in-addr.arpa.=192.0.2.4".parse().unwrap(),
+        ];
+
+        let zone = |name: &str| find(&rules, &Domain::from(name)).map(|v| v.zone.to_string());
+        assert_eq!(zone("www.CORP.example."), Some("corp.example.".to_owned()));
+        assert_eq!(zone("www.example."), Some("example.".to_owned()));
+        assert_eq!(
+            zone("1.0.0.10.in-addr.arpa."),
+            Some("10.in-addr.arpa.".to_owned())
+        );
+        assert_eq!(zone("example.com."), Some(".".to_owned()));
+        assert_eq!(find(&rules[1..], &Domain::from("example.com.")), None);
+    }
+
+    #[tokio::test]
+    async fn test_forward_failover() {
+        // an upstream which never answers
+        let down = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let down_addr = down.local_addr().unwrap();
+
+        let up = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let up_addr = up.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (len, from) = up.recv_from(&mut buf).await.unwrap();
+            let mut res = Message::from_bytes(&buf[..len]).unwrap();
+            res.header.qr = 1;
+            up.send_to(&res.to_vec().await.unwrap(), from)
+                .await
+                .unwrap();
+        });
+
+        let req = Message {
+            header: Header {
+                id: 1234,
+                qr: 0,
+                opcode: 0,
+                aa: 0,
+                tc: 0,
+                rd: 1,
+                ra: 0,
+                z: 0,
+                ad: 0,
+                cd: 0,
+                rcode: RCode::NoError,
+                qd_count: 1,
+                an_count: 0,
+                ns_count: 0,
+                ar_count: 0,
+            },
+            query: Some(Query {
+                qname: Domain::from("example.com."),
+                qtype: QType::A,
+                qclass: 1,
+            }),
+            answer: vec![],
+            authority: vec![],
+            additional: vec![],
+            edns: None,
+        };
+        let upstreams = vec![
+            Upstream {
+                addr: down_addr,
+                timeout: Duration::from_millis(100),
+            },
+            Upstream {
+                addr: up_addr,
+                timeout: Duration::from_secs(3),
+            },
+        ];
+
+        let res = forward(&req, &upstreams, &Config::default()).await.unwrap();
+        assert_eq!(res.header.id, 1234);
+        assert!(is_down(down_addr));
+        assert!(!is_down(up_addr));
+    }
+}
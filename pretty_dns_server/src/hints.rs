@@ -0,0 +1,120 @@
+use pretty_dns_message::domain::Domain;
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+};
+
+/// The root hints published by IANA, used when no hints file is given.
+const DEFAULT_HINTS: &str = include_str!("../named.root");
+
+pub fn default() -> Vec<SocketAddr> {
+    parse(DEFAULT_HINTS).expect("the built-in root hints are broken")
+}
+
+pub fn load(path: &Path) -> io::Result<Vec<SocketAddr>> {
+    parse(&std::fs::read_to_string(path)?)
+}
+
+/// Read the addresses of the root servers from a hints file in the format of
+/// `named.root`, which is a zone file with NS records for the root and A and
+/// AAAA records for the servers. The TTL and class of the records are optional.
+pub fn parse(data: &str) -> io::Result<Vec<SocketAddr>> {
+    let mut names = vec![];
+    let mut addrs = vec![];
+
+    for line in data.lines() {
+        let line = line.split(';').next().unwrap_or_default();
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() {
+            continue;
+        }
+
+        let name = Domain::from(fields[0]);
+        let rest = match fields[1..]
+            .iter()
+            .position(|v| ["NS", "A", "AAAA"].contains(&v.to_uppercase().as_str()))
+        {
+            Some(i) => &fields[i + 1..],
+            None => return Err(invalid(line)),
+        };
+        let (_type, rdata) = match rest {
+            [_type, rdata] => (_type.to_uppercase(), *rdata),
+            _ => return Err(invalid(line)),
+        };
+
+        match _type.as_str() {
+            "NS" if name.eq_ignore_case(&Domain::from(".")) => names.push(Domain::from(rdata)),
+            "NS" => {}
+            _ => {
+                let addr: IpAddr = rdata.parse().map_err(|_| invalid(line))?;
+                addrs.push((name, SocketAddr::new(addr, 53)));
+            }
+        }
+    }
+
+    let roots: Vec<SocketAddr> = names
+        .iter()
+        .flat_map(|name| {
+            addrs
+                .iter()
+                .filter(move |(v, _)| v.eq_ignore_case(name))
+                .map(|(_, addr)| *addr)
+        })
+        .collect();
+    if roots.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no root server in the hints",
+        ));
+    }
+
+    Ok(roots)
+}
+
+fn invalid(line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid root hints: {:?}", line.trim()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{default, parse};
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_default() {
+        let roots = default();
+        assert_eq!(roots.len(), 26);
+        assert_eq!(roots.iter().filter(|v| v.is_ipv6()).count(), 13);
+        assert!(roots.contains(&"202.12.27.33:53".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_parse() {
+        let data = "
+; comment
+.                   3600000 IN NS a.root-servers.net.
+A.ROOT-SERVERS.NET. 3600000 IN A  198.41.0.4 ; trailing comment
+a.root-servers.net.         AAAA  2001:503:ba3e::2:30
+b.root-servers.net.         A     170.247.170.2
+";
+        let roots = parse(data).unwrap();
+        assert_eq!(
+            roots,
+            vec![
+                "198.41.0.4:53".parse::<SocketAddr>().unwrap(),
+                "[2001:503:ba3e::2:30]:53".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_error() {
+        assert!(parse(". 3600000 NS a.root-servers.net.\na.root-servers.net. A 198.41.0").is_err());
+        assert!(parse(". 3600000 NS a.root-servers.net.").is_err());
+        assert!(parse(". 3600000 TXT root").is_err());
+    }
+}
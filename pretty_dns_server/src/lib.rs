@@ -1 +1,3 @@
+pub mod forward;
+pub mod hints;
 pub mod server;
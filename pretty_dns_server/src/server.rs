@@ -1,39 +1,379 @@
-use pretty_dns_cache::cache;
+use crate::{forward, hints};
+use once_cell::sync::Lazy;
+use pretty_dns_cache::{cache, infra, rrset, rrset::Credibility};
 use pretty_dns_client::client;
 use pretty_dns_message::{
-    domain::Domain, header::Header, message::Message, qtype::QType, query::Query,
+    domain::Domain,
+    edns::{Edns, DEFAULT_UDP_PAYLOAD_SIZE},
+    header::{Header, RCode},
+    message::Message,
+    qtype::QType,
+    query::Query,
+    resource::{RData, Resource},
 };
+use socket2::{Socket, Type};
 use std::{
     io,
     net::{IpAddr, Ipv4Addr, SocketAddr},
+    str::FromStr,
     sync::Arc,
 };
-use tokio::net::UdpSocket;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{mpsc, Mutex, Semaphore},
+    time::{self, Duration, Instant},
+};
 use tracing::{debug, error, warn};
 
-#[derive(Debug)]
+/// The longest chain of CNAME and DNAME records which is followed.
+const MAX_CHAIN_LENGTH: usize = 8;
+
+/// How many nameservers without glue may be resolved inside each other.
+const MAX_NS_DEPTH: usize = 4;
+
+/// The root servers learned by priming, which are used instead of the hints
+/// in `Config::roots` once priming has finished.
+static PRIMED_ROOTS: Lazy<std::sync::Mutex<Vec<SocketAddr>>> =
+    Lazy::new(|| std::sync::Mutex::new(vec![]));
+
+/// How often expired records are removed from the cache.
+const CACHE_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The most queries sent to resolve a name in a single zone chain.
+const MAX_REFERRALS: usize = 32;
+
+/// Limits on the number of minimised queries, RFC 9156 2.3.
+const MAX_MINIMISE_COUNT: usize = 10;
+const MINIMISE_ONE_LAB: usize = 4;
+
+#[derive(Debug, Clone)]
 pub struct Config {
-    pub addr: Ipv4Addr,
+    /// Addresses to listen on. IPv6 addresses only accept IPv6, so that
+    /// `0.0.0.0` and `::` can be listened on together.
+    pub addrs: Vec<IpAddr>,
     pub port: u16,
+    /// UDP payload size advertised with EDNS to clients and upstreams.
+    pub udp_payload_size: u16,
+    /// TCP connections without any query for this long are closed.
+    pub tcp_idle_timeout: Duration,
+    pub max_tcp_connections: usize,
+    /// Root servers to start resolving from. They are replaced by the
+    /// servers in the response of the priming query once it is answered.
+    pub roots: Vec<SocketAddr>,
+    /// Which address family to use for the nameservers.
+    pub ip_preference: IpPreference,
+    pub qname_minimisation: QnameMinimisation,
+    /// Queries in the zones of these rules are forwarded instead of
+    /// resolved, see `forward::find`.
+    pub forwarders: Vec<forward::Rule>,
+    /// The most records kept in the cache.
+    pub cache_size: usize,
+    /// How long expired records are kept to be served when they cannot be
+    /// resolved again, RFC 8767. Zero disables serving stale data.
+    pub stale_window: Duration,
+    /// A stale answer is sent when resolving takes longer than this, while
+    /// the resolution continues in the background.
+    pub stale_answer_timeout: Duration,
+    /// Records answered from the cache this many times are resolved again
+    /// before they expire. Zero disables prefetching.
+    pub prefetch_hits: u64,
+    /// The fraction of the original TTL left when a record is prefetched.
+    pub prefetch_fraction: f64,
+    /// Bounds in seconds for the TTLs of cached records.
+    pub min_ttl: u32,
+    pub max_ttl: u32,
+    /// Bounds in seconds for the TTLs of NXDOMAIN and NODATA responses.
+    pub min_negative_ttl: u32,
+    pub max_negative_ttl: u32,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        let ttl_policy = cache::TtlPolicy::default();
+
+        Config {
+            addrs: vec![Ipv4Addr::UNSPECIFIED.into()],
+            port: 53,
+            udp_payload_size: 1232,
+            tcp_idle_timeout: Duration::from_secs(10),
+            max_tcp_connections: 100,
+            roots: hints::default(),
+            ip_preference: IpPreference::Any,
+            qname_minimisation: QnameMinimisation::Relaxed,
+            forwarders: vec![],
+            cache_size: cache::DEFAULT_MAX_ENTRIES,
+            stale_window: Duration::from_secs(86400),
+            stale_answer_timeout: Duration::from_millis(1800),
+            prefetch_hits: 10,
+            prefetch_fraction: 0.1,
+            min_ttl: ttl_policy.min_ttl,
+            max_ttl: ttl_policy.max_ttl,
+            min_negative_ttl: ttl_policy.min_negative_ttl,
+            max_negative_ttl: ttl_policy.max_negative_ttl,
+        }
+    }
+}
+
+impl Config {
+    /// The TTL bounds for the cache, rejected when a minimum is larger than
+    /// its maximum.
+    fn ttl_policy(&self) -> io::Result<cache::TtlPolicy> {
+        if self.min_ttl > self.max_ttl {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "min ttl {} is larger than max ttl {}",
+                    self.min_ttl, self.max_ttl
+                ),
+            ));
+        }
+        if self.min_negative_ttl > self.max_negative_ttl {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "min negative ttl {} is larger than max negative ttl {}",
+                    self.min_negative_ttl, self.max_negative_ttl
+                ),
+            ));
+        }
+
+        Ok(cache::TtlPolicy {
+            min_ttl: self.min_ttl,
+            max_ttl: self.max_ttl,
+            min_negative_ttl: self.min_negative_ttl,
+            max_negative_ttl: self.max_negative_ttl,
+        })
+    }
+
+    pub(crate) fn client(&self) -> client::Config {
+        client::Config {
+            udp_payload_size: self.udp_payload_size,
+            ..client::Config::default()
+        }
+    }
+}
+
+/// The address family policy for upstream nameservers. With `Any` the
+/// servers are tried in order of smoothed RTT only.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IpPreference {
+    Any,
+    PreferIpv4,
+    PreferIpv6,
+    Ipv4Only,
+    Ipv6Only,
+}
+
+impl IpPreference {
+    fn allows(&self, addr: &SocketAddr) -> bool {
+        match self {
+            IpPreference::Ipv4Only => addr.is_ipv4(),
+            IpPreference::Ipv6Only => addr.is_ipv6(),
+            _ => true,
+        }
+    }
+
+    fn prefers(&self, addr: &SocketAddr) -> bool {
+        match self {
+            IpPreference::PreferIpv4 => addr.is_ipv4(),
+            IpPreference::PreferIpv6 => addr.is_ipv6(),
+            _ => true,
+        }
+    }
+}
+
+/// How QNAME minimisation (RFC 9156) is done. `Relaxed` falls back to the
+/// full name when a minimised query fails or gets NXDOMAIN, and `Strict`
+/// trusts those answers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QnameMinimisation {
+    Off,
+    Relaxed,
+    Strict,
+}
+
+impl FromStr for QnameMinimisation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<QnameMinimisation, String> {
+        match s {
+            "off" => Ok(QnameMinimisation::Off),
+            "relaxed" => Ok(QnameMinimisation::Relaxed),
+            "strict" => Ok(QnameMinimisation::Strict),
+            _ => Err(format!("unknown qname minimisation mode: {}", s)),
+        }
+    }
+}
+
+impl FromStr for IpPreference {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<IpPreference, String> {
+        match s {
+            "any" => Ok(IpPreference::Any),
+            "prefer-ipv4" => Ok(IpPreference::PreferIpv4),
+            "prefer-ipv6" => Ok(IpPreference::PreferIpv6),
+            "ipv4-only" => Ok(IpPreference::Ipv4Only),
+            "ipv6-only" => Ok(IpPreference::Ipv6Only),
+            _ => Err(format!("unknown ip preference: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Protocol {
+    Udp,
+    Tcp,
 }
 
 pub async fn start(c: Config) -> io::Result<()> {
     debug!("start server: {:?}", c);
+    let ttl_policy = c.ttl_policy()?;
+
+    // bind every address first, so that a wrong address fails at startup
+    let mut udp = vec![];
+    let mut tcp = vec![];
+    for addr in &c.addrs {
+        let addr = SocketAddr::new(*addr, c.port);
+        udp.push(bind_udp(addr)?);
+        tcp.push(bind_tcp(addr)?);
+    }
+
+    cache::set_max_entries(c.cache_size);
+    rrset::set_max_entries(c.cache_size);
+    cache::set_stale_window(c.stale_window.as_secs() as u32);
+    cache::set_prefetch(c.prefetch_hits, c.prefetch_fraction);
+    cache::set_ttl_policy(ttl_policy);
+    tokio::spawn(sweep_cache());
+
+    // the roots are not used when every query is forwarded. Queries are
+    // resolved from the hints until priming finishes, so that a server
+    // without network still starts at once.
+    if forward::find(&c.forwarders, &Domain::from(".")).is_none() {
+        let c = c.clone();
+        tokio::spawn(async move {
+            let roots = prime(&c).await;
+            *PRIMED_ROOTS.lock().unwrap() = roots;
+        });
+    }
+    if !c.forwarders.is_empty() {
+        let upstreams = c.forwarders.iter().flat_map(|v| v.upstreams.clone());
+        tokio::spawn(forward::health_check(upstreams.collect(), c.client()));
+    }
+
+    let c = Arc::new(c);
+    let connections = Arc::new(Semaphore::new(c.max_tcp_connections));
+
+    // each listener only returns on an error, which stops the server
+    let (tx, mut rx) = mpsc::channel(1);
+    for sock in udp {
+        let tx = tx.clone();
+        let c = c.clone();
+        tokio::spawn(async move { tx.send(serve_udp(sock, c).await).await });
+    }
+    for listener in tcp {
+        let tx = tx.clone();
+        let c = c.clone();
+        let connections = connections.clone();
+        tokio::spawn(async move { tx.send(serve_tcp(listener, connections, c).await).await });
+    }
+    drop(tx);
+
+    match rx.recv().await {
+        Some(result) => result,
+        None => Ok(()),
+    }
+}
+
+fn bind_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let sock = Socket::new(socket2::Domain::for_address(addr), Type::DGRAM, None)?;
+    if addr.is_ipv6() {
+        sock.set_only_v6(true)?;
+    }
+    sock.set_nonblocking(true)?;
+    sock.bind(&addr.into())?;
+
+    UdpSocket::from_std(sock.into())
+}
+
+fn bind_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+    let sock = Socket::new(socket2::Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        sock.set_only_v6(true)?;
+    }
+    sock.set_reuse_address(true)?;
+    sock.set_nonblocking(true)?;
+    sock.bind(&addr.into())?;
+    sock.listen(1024)?;
+
+    TcpListener::from_std(sock.into())
+}
+
+async fn sweep_cache() {
+    let mut interval = time::interval(CACHE_SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        cache::sweep();
+        rrset::sweep();
+    }
+}
+
+/// The primed root servers, or the hints before priming has finished.
+fn roots(c: &Config) -> Vec<SocketAddr> {
+    let primed = PRIMED_ROOTS.lock().unwrap();
+    if primed.is_empty() {
+        return c.roots.clone();
+    }
+
+    primed.clone()
+}
+
+/// Ask the root servers for their NS records and addresses, so that changes
+/// to the root servers after the hints were written are picked up (RFC 8109).
+/// The hints in `c.roots` are kept when priming fails.
+async fn prime(c: &Config) -> Vec<SocketAddr> {
+    let q = Query {
+        qname: Domain::from("."),
+        qtype: QType::NS,
+        qclass: 1,
+    };
+
+    let result = match query_servers(q, ".", &c.roots, c).await {
+        Ok(result) => result,
+        Err(e) => {
+            warn!("priming error, use root hints: {:?}", e);
+            return c.roots.clone();
+        }
+    };
+
+    let (_, roots) = referral(&result);
+    if roots.is_empty() {
+        warn!("priming response has no root server, use root hints");
+        return c.roots.clone();
+    }
+
+    debug!("primed root servers: {:?}", roots);
+    roots
+}
 
-    let sock = UdpSocket::bind((c.addr, c.port)).await?;
+async fn serve_udp(sock: UdpSocket, c: Arc<Config>) -> io::Result<()> {
+    debug!("listen udp: {:?}", sock.local_addr()?);
     let sock = Arc::new(sock);
 
-    let mut buf = [0; 1024];
+    let mut buf = vec![0; Edns::new(c.udp_payload_size).payload_size().into()];
     loop {
         let sock = sock.clone();
+        let c = c.clone();
         let (len, addr) = sock.recv_from(&mut buf).await?;
+        let buf = buf[..len].to_vec();
 
         tokio::spawn(async move {
-            match handler(buf[..len].to_vec()).await {
+            match handler(buf, &c, Protocol::Udp).await {
                 Ok(result) => {
-                    sock.send_to(&result.to_vec().await.unwrap(), addr)
-                        .await
-                        .unwrap();
+                    if let Err(e) = sock.send_to(&result, addr).await {
+                        error!("udp send error: {:?} {:?}", addr, e);
+                    }
                 }
                 Err(e) => {
                     error!("handler error: {:?}", e);
@@ -41,162 +381,1868 @@ pub async fn start(c: Config) -> io::Result<()> {
             }
         });
     }
+}
+
+/// Accept TCP connections, while the number of connections over all the
+/// listeners is limited by `connections`.
+async fn serve_tcp(
+    listener: TcpListener,
+    connections: Arc<Semaphore>,
+    c: Arc<Config>,
+) -> io::Result<()> {
+    debug!("listen tcp: {:?}", listener.local_addr()?);
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let permit = match connections.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                warn!("too many tcp connections, close: {:?}", addr);
+                continue;
+            }
+        };
 
-    return Ok(());
+        let c = c.clone();
+        tokio::spawn(async move {
+            debug!("accept tcp connection: {:?}", addr);
+            if let Err(e) = tcp_connection(stream, c).await {
+                error!("tcp connection error: {:?} {:?}", addr, e);
+            }
+
+            drop(permit);
+        });
+    }
 }
 
-async fn handler(buf: Vec<u8>) -> io::Result<Message> {
-    debug!("receive data: {:?}", buf);
+/// Read queries framed with a two byte length (RFC 1035 4.2.2) until the
+/// client closes the connection or stays idle. Queries are resolved
+/// concurrently, so responses may be sent in a different order (RFC 7766 6.2.1.1).
+async fn tcp_connection(stream: TcpStream, c: Arc<Config>) -> io::Result<()> {
+    let (mut reader, writer) = stream.into_split();
+    let writer = Arc::new(Mutex::new(writer));
 
-    let result = Message::from_bytes(&buf);
-    if result.is_err() {
-        error!("parse messge error: {:?}", result.unwrap_err());
-        return Err(std::io::Error::from(std::io::ErrorKind::Other));
+    loop {
+        let len = match time::timeout(c.tcp_idle_timeout, reader.read_u16()).await {
+            Ok(Ok(len)) => len,
+            Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Ok(Err(e)) => return Err(e),
+            Err(_) => {
+                debug!("tcp connection is idle");
+                return Ok(());
+            }
+        };
+
+        let mut buf = vec![0; len.into()];
+        time::timeout(c.tcp_idle_timeout, reader.read_exact(&mut buf)).await??;
+
+        let writer = writer.clone();
+        let c = c.clone();
+        tokio::spawn(async move {
+            match handler(buf, &c, Protocol::Tcp).await {
+                Ok(result) => {
+                    let mut writer = writer.lock().await;
+                    let sent = async {
+                        writer.write_u16(result.len() as u16).await?;
+                        writer.write_all(&result).await
+                    };
+
+                    if let Err(e) = sent.await {
+                        error!("tcp write error: {:?}", e);
+                    }
+                }
+                Err(e) => {
+                    error!("handler error: {:?}", e);
+                }
+            }
+        });
     }
+}
+
+async fn handler(buf: Vec<u8>, c: &Config, protocol: Protocol) -> io::Result<Vec<u8>> {
+    debug!("receive data: {:?} {:?}", protocol, buf);
 
-    let (_, req) = result.unwrap();
+    let req = match Message::from_bytes(&buf) {
+        Ok(req) => req,
+        Err(e) => {
+            warn!("parse message error: {}", e);
+            return match format_error(&buf) {
+                Some(result) => result.to_vec().await,
+                None => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            };
+        }
+    };
     debug!("parsed request: {:?}", req);
 
-    return resolve(req, "202.12.27.33:53".parse().unwrap()).await;
+    // never answer a response, or two servers could keep answering each other
+    if req.header.qr == 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "received a response",
+        ));
+    }
+
+    let header = req.header.clone();
+    let query = req.query.clone();
+    let req_edns = req.edns.clone();
+    let mut result = match check(&req) {
+        Some(rcode) => {
+            debug!("reject request: {:?}", rcode);
+            error_response(&header, query, rcode)
+        }
+        None => match resolve(req, c).await {
+            Ok(result) => result,
+            Err(e) => {
+                error!("resolve error: {:?}", e);
+                error_response(&header, query, RCode::ServFail)
+            }
+        },
+    };
+    result.edns = req_edns.as_ref().map(|v| Edns {
+        dnssec_ok: v.dnssec_ok,
+        ..Edns::new(c.udp_payload_size)
+    });
+
+    // the length of a TCP message is sent in two bytes
+    if protocol == Protocol::Tcp {
+        return truncate(result, u16::MAX.into()).await;
+    }
+
+    // the smaller of the payload sizes of the client and this server
+    let limit = req_edns
+        .map(|v| v.payload_size())
+        .unwrap_or(DEFAULT_UDP_PAYLOAD_SIZE)
+        .min(Edns::new(c.udp_payload_size).payload_size());
+
+    truncate(result, limit.into()).await
+}
+
+/// Return the error to answer with when the request is not resolved.
+fn check(req: &Message) -> Option<RCode> {
+    if req.header.opcode != 0 {
+        return Some(RCode::NotImp);
+    }
+
+    match req.query {
+        None => Some(RCode::FormErr),
+        // only the IN class is resolved, RFC 1035 4.3.2
+        Some(ref q) if q.qclass != 1 => Some(RCode::Refused),
+        Some(_) => None,
+    }
+}
+
+/// Build a FORMERR response for a request which could not be parsed. The
+/// question is echoed when it can be read, and nothing is sent back when
+/// even the header is broken since the id is unknown.
+fn format_error(buf: &[u8]) -> Option<Message> {
+    let (data, header) = Header::read(buf).ok()?;
+    if header.qr == 1 {
+        return None;
+    }
+
+    let query = if header.qd_count == 1 {
+        Query::read(buf)(data).ok().map(|(_, q)| q)
+    } else {
+        None
+    };
+
+    Some(error_response(&header, query, RCode::FormErr))
+}
+
+/// The header of a response to `req`, whether it is resolved, answered
+/// from the cache or an error. Recursion is available, the answer is never
+/// authoritative since it is not from a zone of this server, and nothing is
+/// validated, so AD is never set.
+fn response_header(req: &Header, rcode: RCode) -> Header {
+    Header {
+        id: req.id,
+        qr: 1,
+        opcode: req.opcode,
+        aa: 0,
+        tc: 0,
+        rd: req.rd,
+        ra: 1,
+        z: 0,
+        ad: 0,
+        cd: req.cd,
+        rcode,
+        qd_count: 0,
+        an_count: 0,
+        ns_count: 0,
+        ar_count: 0,
+    }
+}
+
+fn error_response(req: &Header, query: Option<Query>, rcode: RCode) -> Message {
+    Message {
+        header: Header {
+            qd_count: query.is_some() as u16,
+            ..response_header(req, rcode)
+        },
+        query,
+        answer: vec![],
+        authority: vec![],
+        additional: vec![],
+        edns: None,
+    }
+}
+
+/// Serialize the message, and when it does not fit in `limit` bytes drop
+/// the records and set the TC bit so that the client retries over TCP.
+async fn truncate(mut message: Message, limit: usize) -> io::Result<Vec<u8>> {
+    let data = message.to_vec().await?;
+    if data.len() <= limit {
+        return Ok(data);
+    }
+
+    debug!("truncate response: {} > {}", data.len(), limit);
+    message.header.tc = 1;
+    message.answer.clear();
+    message.authority.clear();
+    message.additional.clear();
+
+    message.to_vec().await
 }
 
-async fn resolve(req: Message, root_ns: SocketAddr) -> io::Result<Message> {
+/// Answer from the cache, or resolve the query. Expired records in the
+/// cache are served when resolving fails or is slow, RFC 8767.
+async fn resolve(req: Message, c: &Config) -> io::Result<Message> {
     if req.query.is_none() {
         return Err(std::io::Error::from(std::io::ErrorKind::Other));
     }
 
-    let q = req.query.unwrap();
+    let q = req.query.clone().unwrap();
     let query_domain = q.qname.to_string();
-    if let Some(cache_data) = cache::resolve(query_domain.clone(), q.qtype) {
-        let an_count = cache_data.answer.len() as u16;
-        let ns_count = cache_data.authority.len() as u16;
-        let ar_count = cache_data.additional.len() as u16;
+    let cached = match cached_answer(&q) {
+        Some(answer) => Some(cache::CacheData {
+            rcode: RCode::NoError,
+            answer,
+            authority: vec![],
+            additional: vec![],
+        }),
+        None => cache::resolve(query_domain.clone(), q.qtype),
+    };
+    if let Some(cache_data) = cached {
+        if cache::take_prefetch(query_domain.clone(), q.qtype) {
+            debug!("prefetch: {:?} {:?}", query_domain, q.qtype);
+            refresh(&req, c);
+        }
 
-        return Ok(Message {
-            header: Header {
-                id: req.header.id,
-                qr: 1,
-                opcode: 0,
-                aa: 0,
-                tc: 0,
-                rd: 1,
-                ra: 0,
-                z: 0,
-                ad: 1,
-                cd: 0,
-                rcode: 0,
-                qd_count: 1,
-                an_count: an_count,
-                ns_count: ns_count,
-                ar_count: ar_count,
-            },
-            query: Some(q),
-            answer: cache_data.answer,
-            authority: cache_data.authority,
-            additional: cache_data.additional,
-        });
+        return Ok(cached_response(&req, cache_data));
     }
 
-    let mut resolve_list = vec![];
-    for v in get_domain_list(&query_domain) {
-        resolve_list.push(v.clone());
+    if c.stale_window.is_zero() {
+        return lookup(&req, c).await;
     }
 
-    resolve_list.reverse();
-    debug!("resolve list for ns: {:?}", &resolve_list);
-
-    let mut ns = root_ns;
-    for r in resolve_list {
-        let q = Query {
-            qname: Domain::from(r),
-            qtype: QType::NS.into(),
-            qclass: 1,
-        };
+    // owned, so that it can keep running after a stale answer is served
+    let mut fresh = Box::pin({
+        let (req, c) = (req.clone(), c.clone());
+        async move { lookup(&req, &c).await }
+    });
+    let result = match time::timeout(c.stale_answer_timeout, &mut fresh).await {
+        Ok(result) => result,
+        Err(_) => match cache::resolve_stale(query_domain.clone(), q.qtype) {
+            Some(cache_data) => {
+                warn!(
+                    "resolve is slow, serve stale: {:?} {:?}, {} stale answers in total",
+                    query_domain,
+                    q.qtype,
+                    cache::stale_served()
+                );
 
-        debug!(
-            "try to resolve ns for {:?} by {:?}",
-            q.qname.to_string(),
-            ns
-        );
-        let _result = client::resolve(q, ns).await?;
-        debug!("resolve result: {:?}", _result);
+                tokio::spawn(async move {
+                    if let Err(e) = fresh.await {
+                        debug!("refresh cache error: {:?}", e);
+                    }
+                });
 
-        for a in _result.additional {
-            if a._type != QType::A {
-                continue;
+                return Ok(cached_response(&req, cache_data));
             }
+            None => fresh.await,
+        },
+    };
 
-            let rdata = a.rdata.into();
-            if rdata.len() != 4 {
-                warn!("rdata is not wrong length: {:?}", rdata);
-                continue;
+    match result {
+        Ok(result) if result.header.rcode != RCode::ServFail => Ok(result),
+        result => match cache::resolve_stale(query_domain.clone(), q.qtype) {
+            Some(cache_data) => {
+                warn!(
+                    "resolve failed, serve stale: {:?} {:?}, {} stale answers in total",
+                    query_domain,
+                    q.qtype,
+                    cache::stale_served()
+                );
+                Ok(cached_response(&req, cache_data))
             }
+            None => result,
+        },
+    }
+}
 
-            ns = SocketAddr::new(
-                IpAddr::V4(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3])),
-                53,
-            );
+/// Build an answer from the cached RRsets, following CNAME records. Only
+/// RRsets from answer sections are given to clients, RFC 2181 5.4.1.
+fn cached_answer(q: &Query) -> Option<Vec<Resource>> {
+    let rrset = |name: &Domain, qtype: QType| match rrset::resolve(name, qtype, q.qclass) {
+        Some((records, credibility)) if credibility >= Credibility::NonAuthAnswer => Some(records),
+        _ => None,
+    };
+
+    let mut answer = vec![];
+    let mut name = q.qname.clone();
+    for _ in 0..=MAX_CHAIN_LENGTH {
+        if let Some(records) = rrset(&name, q.qtype) {
+            answer.extend(records);
+            return Some(answer);
+        }
+        if q.qtype == QType::CNAME {
+            return None;
         }
+
+        let records = rrset(&name, QType::CNAME)?;
+        name = match records.first()?.rdata {
+            RData::CNAME(ref target) => target.clone(),
+            _ => return None,
+        };
+        answer.extend(records);
     }
 
-    let qname = q.qname;
-    let domain = qname.to_string();
-    let query = Query {
-        qname: qname,
-        qtype: q.qtype,
-        qclass: 1,
+    None
+}
+
+/// Resolve the query without the cache, and store the result.
+async fn lookup(req: &Message, c: &Config) -> io::Result<Message> {
+    let q = req.query.clone().unwrap();
+    let mut result = match forward::find(&c.forwarders, &q.qname) {
+        Some(rule) => {
+            debug!("forward query for zone {:?}", rule.zone.to_string());
+            // the client gets the response as it is, but only the records in
+            // the zone of the rule are trusted for other queries
+            let result = forward::forward(req, &rule.upstreams, c).await?;
+            cache_rrsets(&bailiwick(result.clone(), &rule.zone));
+
+            result
+        }
+        None => resolve_chain(&q, c, &[]).await?,
+    };
+    // the header of the nameserver or upstream is not passed on
+    result.header = Header {
+        qd_count: result.header.qd_count,
+        an_count: result.header.an_count,
+        ns_count: result.header.ns_count,
+        ar_count: result.header.ar_count,
+        ..response_header(&req.header, result.header.rcode)
     };
-    debug!(
-        "try to resolve query for {:?} by {:?}",
-        query.qname.to_string(),
-        ns
-    );
-    let mut result = client::resolve(query, ns).await?;
-    result.header.id = req.header.id;
 
     debug!("query result: {:?}", result);
     cache::cache(
-        domain,
+        q.qname.to_string(),
         q.qtype,
+        result.header.rcode,
         &result.answer,
         &result.authority,
         &result.additional,
-    )
-    .unwrap();
+    );
 
     Ok(result)
 }
 
-pub fn get_domain_list(domain: &str) -> Vec<String> {
-    let mut domain = domain.to_owned();
-    if !domain.ends_with(".") {
-        domain += ".";
+/// Resolve the query again in the background to refresh the cache.
+fn refresh(req: &Message, c: &Config) {
+    let (req, c) = (req.clone(), c.clone());
+    tokio::spawn(async move {
+        if let Err(e) = lookup(&req, &c).await {
+            debug!("refresh cache error: {:?}", e);
+        }
+    });
+}
+
+fn cached_response(req: &Message, cache_data: cache::CacheData) -> Message {
+    let an_count = cache_data.answer.len() as u16;
+    let ns_count = cache_data.authority.len() as u16;
+    let ar_count = cache_data.additional.len() as u16;
+
+    Message {
+        header: Header {
+            qd_count: 1,
+            an_count,
+            ns_count,
+            ar_count,
+            ..response_header(&req.header, cache_data.rcode)
+        },
+        query: req.query.clone(),
+        answer: cache_data.answer,
+        authority: cache_data.authority,
+        additional: cache_data.additional,
+        edns: None,
+    }
+}
+
+/// Resolve the query and follow CNAME and DNAME records. Each target that
+/// is not answered in the same response is resolved again, since it may
+/// belong to another zone, see `resolve_target`. The answer section of the result
+/// holds the whole chain. `ns_chain` is the nameservers whose addresses are
+/// being resolved while this query runs, see `iterate`.
+async fn resolve_chain(q: &Query, c: &Config, ns_chain: &[Domain]) -> io::Result<Message> {
+    let mut answer = vec![];
+    let mut chain = vec![q.qname.clone()];
+    let mut qname = q.qname.clone();
+
+    loop {
+        let query = Query {
+            qname: qname.clone(),
+            qtype: q.qtype,
+            qclass: q.qclass,
+        };
+        let mut result = resolve_target(query, c, ns_chain).await?;
+
+        let records = std::mem::take(&mut result.answer);
+        match follow_chain(&qname, q.qtype, &records, &mut answer, &mut chain)? {
+            Some(next) => {
+                debug!(
+                    "follow alias: {:?} -> {:?}",
+                    qname.to_string(),
+                    next.to_string()
+                );
+                qname = next;
+            }
+            None => {
+                result.query = Some(q.clone());
+                result.answer = answer;
+                return Ok(result);
+            }
+        }
+    }
+}
+
+/// Resolve one name of a chain, forwarding it when it is in a forwarded
+/// zone and iterating from the closest known delegation otherwise.
+async fn resolve_target(query: Query, c: &Config, ns_chain: &[Domain]) -> io::Result<Message> {
+    match forward::find(&c.forwarders, &query.qname) {
+        Some(rule) => {
+            debug!("forward query for zone {:?}", rule.zone.to_string());
+            let result = forward::forward_query(query, &rule.upstreams, c).await?;
+            let result = bailiwick(result, &rule.zone);
+            cache_rrsets(&result);
+
+            Ok(result)
+        }
+        None => iterate(query, c, ns_chain).await,
+    }
+}
+
+/// Collect the records for `qname` from `records` into `answer`, following
+/// aliases as far as the records go. Returns the name that still has to be
+/// resolved when the chain leaves the records. Every name in the chain is
+/// kept in `chain` to detect loops and limit its length.
+fn follow_chain(
+    qname: &Domain,
+    qtype: QType,
+    records: &[Resource],
+    answer: &mut Vec<Resource>,
+    chain: &mut Vec<Domain>,
+) -> io::Result<Option<Domain>> {
+    let mut name = qname.clone();
+
+    loop {
+        let found: Vec<Resource> = records
+            .iter()
+            .filter(|r| r._type == qtype && r.name.eq_ignore_case(&name))
+            .cloned()
+            .collect();
+        if !found.is_empty() {
+            answer.extend(found);
+            return Ok(None);
+        }
+
+        let next = match alias(&name, qtype, records, answer) {
+            Some(next) => next,
+            // no data for the name, or the name has to be asked for
+            None if name.eq_ignore_case(qname) => return Ok(None),
+            None => return Ok(Some(name)),
+        };
+
+        if chain.iter().any(|v| v.eq_ignore_case(&next)) {
+            return Err(io::Error::new(io::ErrorKind::Other, "alias loop"));
+        }
+        if chain.len() > MAX_CHAIN_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "alias chain is too long",
+            ));
+        }
+
+        chain.push(next.clone());
+        name = next;
     }
+}
 
-    let mut result = vec![];
-    let mut v: Vec<&str> = domain.split(".").collect();
+/// Find the CNAME for `name`, or the DNAME of one of its ancestors, add it
+/// to `answer` and return its target.
+fn alias(
+    name: &Domain,
+    qtype: QType,
+    records: &[Resource],
+    answer: &mut Vec<Resource>,
+) -> Option<Domain> {
+    let cname = records
+        .iter()
+        .find(|r| qtype != QType::CNAME && r._type == QType::CNAME && r.name.eq_ignore_case(name));
 
-    while v.len() > 0 {
-        if v[0] == "" {
-            break;
+    let dname = records.iter().find_map(|r| match r.rdata {
+        RData::DNAME(ref target) if qtype != QType::DNAME && !r.name.eq_ignore_case(name) => {
+            name.replace_suffix(&r.name, target).map(|v| (r, v))
         }
+        _ => None,
+    });
+
+    if let Some((r, target)) = dname {
+        answer.push(r.clone());
+        // the CNAME synthesized from the DNAME, RFC 6672 3.4
+        answer.push(match cname {
+            Some(cname) => cname.clone(),
+            None => Resource {
+                name: name.clone(),
+                _type: QType::CNAME,
+                class: r.class,
+                ttl: r.ttl,
+                rdata: RData::CNAME(target.clone()),
+            },
+        });
 
-        result.push(v.join("."));
-        v.reverse();
-        v.pop();
-        v.reverse();
+        return Some(target);
     }
 
-    return result;
+    match cname?.rdata {
+        RData::CNAME(ref target) => {
+            answer.push(cname?.clone());
+            Some(target.clone())
+        }
+        _ => None,
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::get_domain_list;
+/// Store the RRsets of a response from a nameserver or an upstream, which
+/// answer later queries, see `cached_answer`.
+fn cache_rrsets(result: &Message) {
+    rrset::cache(
+        result.header.aa == 1,
+        &result.answer,
+        &result.authority,
+        &result.additional,
+    );
+}
 
-    #[tokio::test]
-    async fn test_get_domain_list() {
-        let list = get_domain_list("www.google.com.");
-        assert_eq!(list, vec!["www.google.com.", "google.com.", "com."]);
+/// Walk down the delegations from the root to the zone of the query, and
+/// send the query to its nameservers. With QNAME minimisation (RFC 9156)
+/// each server is only asked for one label more than its zone, with the A
+/// type, until the full name is reached. The addresses of nameservers
+/// without glue are resolved from the root as well, while `ns_chain` holds
+/// the names which are already being resolved to stop cycles.
+async fn iterate(q: Query, c: &Config, ns_chain: &[Domain]) -> io::Result<Message> {
+    let labels = q.qname.labels();
+
+    // start from the closest delegation in the cache, or from the root
+    let (mut zone, mut servers) = match rrset::delegation(&q.qname) {
+        Some((zone, addrs)) => {
+            let servers = addrs.into_iter().map(|v| SocketAddr::new(v, 53)).collect();
+            (zone, servers)
+        }
+        None => (Domain::from("."), roots(c)),
+    };
+    let mut from_cache = !zone.labels().is_empty();
+    let mut minimise = c.qname_minimisation != QnameMinimisation::Off;
+    // the number of labels of the name asked for last
+    let mut depth = zone.labels().len();
+    let mut minimised = 0;
+
+    for _ in 0..MAX_REFERRALS {
+        let next = if minimise {
+            next_depth(depth, labels.len(), minimised)
+        } else {
+            labels.len()
+        };
+
+        let query = if next < labels.len() {
+            minimised += 1;
+            Query {
+                qname: Domain::from_labels(&labels[labels.len() - next..]),
+                qtype: QType::A,
+                qclass: q.qclass,
+            }
+        } else {
+            q.clone()
+        };
+
+        debug!(
+            "try to resolve {:?} {:?} by {:?}",
+            query.qname.to_string(),
+            query.qtype,
+            servers
+        );
+        let result = match query_servers(query, &zone.to_string(), &servers, c).await {
+            Ok(result) => result,
+            Err(e) if from_cache => {
+                warn!("cached nameservers error, start from the root: {:?}", e);
+                zone = Domain::from(".");
+                servers = roots(c);
+                depth = 0;
+                from_cache = false;
+                continue;
+            }
+            Err(e) if next < labels.len() && c.qname_minimisation == QnameMinimisation::Relaxed => {
+                warn!("minimised query error, send the full name: {:?}", e);
+                minimise = false;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        debug!("resolve result: {:?}", result);
+        from_cache = false;
+        let result = bailiwick(result, &zone);
+        cache_rrsets(&result);
+
+        if let Some(child) = delegation(&result, &zone, &q.qname) {
+            servers = delegation_servers(&result, c, ns_chain).await?;
+            depth = child.labels().len();
+            zone = child;
+            continue;
+        }
+
+        if next == labels.len() {
+            return Ok(result);
+        }
+
+        if result.header.rcode == RCode::NXDomain {
+            // nothing exists below a name which does not exist (RFC 8020),
+            // but some servers answer NXDOMAIN for empty non-terminals
+            if c.qname_minimisation == QnameMinimisation::Strict {
+                return Ok(result);
+            }
+
+            debug!("NXDOMAIN for a minimised query, send the full name");
+            minimise = false;
+            continue;
+        }
+
+        // an empty non-terminal or a name inside the zone
+        depth = next;
+    }
+
+    Err(io::Error::new(io::ErrorKind::Other, "too many referrals"))
+}
+
+/// How many labels of the query name to ask for next. One more label than
+/// the last query, until `MINIMISE_ONE_LAB` queries were sent, after which
+/// labels are added so that at most `MAX_MINIMISE_COUNT` queries are
+/// minimised (RFC 9156 2.3).
+fn next_depth(depth: usize, length: usize, minimised: usize) -> usize {
+    if minimised < MINIMISE_ONE_LAB {
+        return (depth + 1).min(length);
+    }
+
+    let remaining = MAX_MINIMISE_COUNT.saturating_sub(minimised);
+    if remaining <= 1 {
+        return length;
+    }
+
+    let step = ((length - depth) / remaining).max(1);
+    (depth + step).min(length)
+}
+
+/// Return the zone that a response delegates to, when it is a referral to a
+/// zone below `zone` which contains `qname`.
+fn delegation(res: &Message, zone: &Domain, qname: &Domain) -> Option<Domain> {
+    if res.header.rcode != RCode::NoError || res.header.aa == 1 || !res.answer.is_empty() {
+        return None;
+    }
+
+    res.authority.iter().find_map(|r| match r.rdata {
+        RData::NS(_)
+            if !r.name.eq_ignore_case(zone)
+                && r.name.is_subdomain_of(zone)
+                && qname.is_subdomain_of(&r.name) =>
+        {
+            Some(r.name.clone())
+        }
+        _ => None,
+    })
+}
+
+/// Return the addresses of the nameservers in a referral, from the glue or by
+/// resolving the nameserver names.
+async fn delegation_servers(
+    res: &Message,
+    c: &Config,
+    ns_chain: &[Domain],
+) -> io::Result<Vec<SocketAddr>> {
+    let (names, glue) = referral(res);
+    if !glue.is_empty() {
+        return Ok(glue);
+    }
+
+    for name in names {
+        let addrs = resolve_ns_address(&name, c, ns_chain).await;
+        if !addrs.is_empty() {
+            return Ok(addrs);
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "no address for the nameservers",
+    ))
+}
+
+/// Send the query to the nameservers of `zone` in order of smoothed RTT
+/// until one of them answers. Servers which time out or answer SERVFAIL are
+/// skipped, and servers which answer REFUSED are marked lame for the zone.
+async fn query_servers(
+    q: Query,
+    zone: &str,
+    servers: &[SocketAddr],
+    c: &Config,
+) -> io::Result<Message> {
+    let mut servers: Vec<SocketAddr> = servers
+        .iter()
+        .filter(|v| c.ip_preference.allows(v))
+        .cloned()
+        .collect();
+    infra::sort(zone, &mut servers);
+    servers.sort_by_key(|v| !c.ip_preference.prefers(v));
+
+    let client_config = c.client();
+    let mut last_error = io::Error::new(io::ErrorKind::Other, "no nameserver");
+    for ns in servers {
+        let start = Instant::now();
+        let result = match client::resolve(q.clone(), ns, &client_config).await {
+            Ok(result) => result,
+            Err(e) => {
+                warn!("nameserver error: {:?} {:?}", ns, e);
+                infra::failed(ns.ip(), client_config.timeout);
+                last_error = e;
+                continue;
+            }
+        };
+        infra::update_rtt(ns.ip(), start.elapsed());
+
+        match result.header.rcode {
+            RCode::ServFail => {
+                warn!("nameserver answered SERVFAIL: {:?}", ns);
+                last_error = io::Error::new(io::ErrorKind::Other, "SERVFAIL");
+            }
+            RCode::Refused => {
+                warn!("nameserver is lame: {:?} {:?}", ns, zone);
+                infra::mark_lame(ns.ip(), zone);
+                last_error = io::Error::new(io::ErrorKind::Other, "REFUSED");
+            }
+            _ => return Ok(result),
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Remove the records which are not at or below `zone`, the zone of the
+/// server which sent them, since a server is only trusted for its own zone.
+/// An upstream is trusted for the zone of its forwarding rule.
+/// Otherwise the server for `example.com.` could answer with records for
+/// `example.net.` and poison the cache.
+fn bailiwick(mut res: Message, zone: &Domain) -> Message {
+    for section in [&mut res.answer, &mut res.authority, &mut res.additional] {
+        section.retain(|r| {
+            if r.name.is_subdomain_of(zone) {
+                return true;
+            }
+
+            warn!(
+                "discard out-of-bailiwick record: {:?} {:?} from zone {:?}",
+                r.name.to_string(),
+                r._type,
+                zone.to_string()
+            );
+            false
+        });
+    }
+
+    res.header.an_count = res.answer.len() as u16;
+    res.header.ns_count = res.authority.len() as u16;
+    res.header.ar_count = res.additional.len() as u16;
+
+    res
+}
+
+/// Return the nameservers a response delegates to, and the addresses in
+/// its glue records.
+fn referral(res: &Message) -> (Vec<Domain>, Vec<SocketAddr>) {
+    let names: Vec<Domain> = res
+        .answer
+        .iter()
+        .chain(res.authority.iter())
+        .filter_map(|r| match r.rdata {
+            RData::NS(ref name) => Some(name.clone()),
+            _ => None,
+        })
+        .collect();
+
+    let glue = res
+        .additional
+        .iter()
+        .filter_map(|r| match r.rdata {
+            RData::A(addr) if names.iter().any(|v| v.eq_ignore_case(&r.name)) => {
+                Some(SocketAddr::new(IpAddr::V4(addr), 53))
+            }
+            RData::AAAA(addr) if names.iter().any(|v| v.eq_ignore_case(&r.name)) => {
+                Some(SocketAddr::new(IpAddr::V6(addr), 53))
+            }
+            _ => None,
+        })
+        .collect();
+
+    (names, glue)
+}
+
+/// Resolve the addresses of a nameserver which has no glue, in the address
+/// families allowed by `c.ip_preference`.
+async fn resolve_ns_address(name: &Domain, c: &Config, ns_chain: &[Domain]) -> Vec<SocketAddr> {
+    let cached: Vec<SocketAddr> = rrset::addresses(name)
+        .into_iter()
+        .map(|v| SocketAddr::new(v, 53))
+        .filter(|v| c.ip_preference.allows(v))
+        .collect();
+    if !cached.is_empty() {
+        debug!("found cached nameserver address: {:?}", name.to_string());
+        return cached;
+    }
+
+    if ns_chain.iter().any(|v| v.eq_ignore_case(name)) {
+        warn!("nameserver cycle: {:?}", name.to_string());
+        return vec![];
+    }
+    if ns_chain.len() >= MAX_NS_DEPTH {
+        warn!("too deep to resolve nameserver: {:?}", name.to_string());
+        return vec![];
+    }
+
+    let mut ns_chain = ns_chain.to_vec();
+    ns_chain.push(name.clone());
+
+    debug!("resolve nameserver without glue: {:?}", name.to_string());
+    let qtypes = match c.ip_preference {
+        IpPreference::Ipv4Only => vec![QType::A],
+        IpPreference::Ipv6Only => vec![QType::AAAA],
+        _ => vec![QType::A, QType::AAAA],
+    };
+
+    let mut addrs = vec![];
+    for qtype in qtypes {
+        let q = Query {
+            qname: name.clone(),
+            qtype,
+            qclass: 1,
+        };
+
+        let result = match Box::pin(resolve_chain(&q, c, &ns_chain)).await {
+            Ok(result) => result,
+            Err(e) => {
+                warn!(
+                    "resolve nameserver error: {:?} {:?} {:?}",
+                    name.to_string(),
+                    qtype,
+                    e
+                );
+                continue;
+            }
+        };
+
+        addrs.extend(result.answer.iter().filter_map(|r| match r.rdata {
+            RData::A(addr) => Some(SocketAddr::new(IpAddr::V4(addr), 53)),
+            RData::AAAA(addr) => Some(SocketAddr::new(IpAddr::V6(addr), 53)),
+            _ => None,
+        }));
+    }
+
+    addrs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        bailiwick, bind_tcp, bind_udp, delegation, follow_chain, handler, iterate, next_depth,
+        prime, query_servers, referral, resolve, resolve_chain, serve_tcp, start, truncate, Config,
+        IpPreference, Protocol, QnameMinimisation,
+    };
+    use pretty_dns_cache::{cache, infra, rrset};
+    use pretty_dns_message::{
+        domain::Domain,
+        header::{Header, RCode},
+        message::Message,
+        qtype::QType,
+        query::Query,
+        resource::{RData, Resource},
+    };
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        sync::Arc,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpStream, UdpSocket},
+        sync::Semaphore,
+        time::{self, Duration},
+    };
+
+    fn record(name: &str, rdata: RData) -> Resource {
+        let _type = match rdata {
+            RData::A(_) => QType::A,
+            RData::CNAME(_) => QType::CNAME,
+            RData::DNAME(_) => QType::DNAME,
+            RData::NS(_) => QType::NS,
+            _ => unreachable!(),
+        };
+
+        Resource {
+            name: Domain::from(name),
+            _type,
+            class: 1,
+            ttl: 300,
+            rdata,
+        }
+    }
+
+    /// Start a nameserver on localhost which answers every query with the
+    /// response `answer` makes from it.
+    async fn mock_ns(answer: fn(&mut Message)) -> SocketAddr {
+        mock_ns_on("127.0.0.1:0", answer).await
+    }
+
+    async fn mock_ns_on(addr: &str, answer: fn(&mut Message)) -> SocketAddr {
+        let sock = UdpSocket::bind(addr).await.unwrap();
+        let addr = sock.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, from) = sock.recv_from(&mut buf).await.unwrap();
+                let mut res = Message::from_bytes(&buf[..len]).unwrap();
+                res.header.qr = 1;
+                res.edns = None;
+                answer(&mut res);
+                sock.send_to(&res.to_vec().await.unwrap(), from)
+                    .await
+                    .unwrap();
+            }
+        });
+
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_truncate() {
+        let message = Message {
+            header: Header {
+                id: 1,
+                qr: 1,
+                opcode: 0,
+                aa: 0,
+                tc: 0,
+                rd: 1,
+                ra: 0,
+                z: 0,
+                ad: 0,
+                cd: 0,
+                rcode: RCode::NoError,
+                qd_count: 1,
+                an_count: 1,
+                ns_count: 0,
+                ar_count: 0,
+            },
+            query: Some(Query {
+                qname: Domain::from("example.com."),
+                qtype: QType::TXT,
+                qclass: 1,
+            }),
+            answer: vec![Resource {
+                name: Domain::from("example.com."),
+                _type: QType::TXT,
+                class: 1,
+                ttl: 300,
+                rdata: RData::TXT(vec![vec![97; 255]; 4]),
+            }],
+            authority: vec![],
+            additional: vec![],
+            edns: None,
+        };
+
+        let data = truncate(message, 512).await.unwrap();
+        let result = Message::from_bytes(&data).unwrap();
+        assert_eq!(result.header.tc, 1);
+        assert_eq!(result.answer, vec![]);
+        assert!(result.query.is_some());
+    }
+
+    fn config() -> Config {
+        Config {
+            addrs: vec![Ipv4Addr::LOCALHOST.into()],
+            port: 0,
+            udp_payload_size: 1232,
+            tcp_idle_timeout: Duration::from_secs(1),
+            max_tcp_connections: 1,
+            roots: vec![],
+            ip_preference: IpPreference::Any,
+            qname_minimisation: QnameMinimisation::Relaxed,
+            forwarders: vec![],
+            cache_size: 100,
+            stale_window: Duration::ZERO,
+            stale_answer_timeout: Duration::from_millis(1800),
+            prefetch_hits: 0,
+            prefetch_fraction: 0.1,
+            ..Config::default()
+        }
+    }
+
+    fn request(opcode: u8, qclass: u16) -> Message {
+        Message {
+            header: Header {
+                id: 1234,
+                qr: 0,
+                opcode,
+                aa: 0,
+                tc: 0,
+                rd: 1,
+                ra: 0,
+                z: 0,
+                ad: 0,
+                cd: 0,
+                rcode: RCode::NoError,
+                qd_count: 1,
+                an_count: 0,
+                ns_count: 0,
+                ar_count: 0,
+            },
+            query: Some(Query {
+                qname: Domain::from("example.com."),
+                qtype: QType::A,
+                qclass,
+            }),
+            answer: vec![],
+            authority: vec![],
+            additional: vec![],
+            edns: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handler_error_rcode() {
+        let req = request(2, 1);
+        let data = handler(req.to_vec().await.unwrap(), &config(), Protocol::Udp)
+            .await
+            .unwrap();
+        let result = Message::from_bytes(&data).unwrap();
+        assert_eq!(result.header.id, 1234);
+        assert_eq!(result.header.qr, 1);
+        assert_eq!(result.header.rcode, RCode::NotImp);
+        assert_eq!(result.query, req.query);
+
+        let req = request(0, 3);
+        let data = handler(req.to_vec().await.unwrap(), &config(), Protocol::Tcp)
+            .await
+            .unwrap();
+        let result = Message::from_bytes(&data).unwrap();
+        assert_eq!(result.header.rcode, RCode::Refused);
+        assert_eq!(result.query, req.query);
+    }
+
+    #[tokio::test]
+    async fn test_handler_format_error() {
+        let req = request(0, 1);
+        let mut data = req.to_vec().await.unwrap();
+        data.push(0);
+
+        let data = handler(data, &config(), Protocol::Udp).await.unwrap();
+        let result = Message::from_bytes(&data).unwrap();
+        assert_eq!(result.header.id, 1234);
+        assert_eq!(result.header.rcode, RCode::FormErr);
+        assert_eq!(result.query, req.query);
+
+        assert!(handler(vec![4, 210, 1], &config(), Protocol::Udp)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_chain() {
+        let ns = mock_ns(|res| {
+            let q = res.query.as_ref().unwrap();
+            res.answer = match (q.qname.to_string().as_str(), q.qtype) {
+                ("www.example.com.", QType::A) => vec![
+                    record(
+                        "www.example.com.",
+                        RData::CNAME(Domain::from("web.example.com.")),
+                    ),
+                    record(
+                        "web.example.com.",
+                        RData::CNAME(Domain::from("web.example.net.")),
+                    ),
+                ],
+                ("web.example.net.", QType::A) => vec![record(
+                    "web.example.net.",
+                    RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+                )],
+                _ => vec![],
+            };
+        })
+        .await;
+
+        let q = Query {
+            qname: Domain::from("www.example.com."),
+            qtype: QType::A,
+            qclass: 1,
+        };
+        let c = Config {
+            roots: vec![ns],
+            ..config()
+        };
+        let result = resolve_chain(&q, &c, &[]).await.unwrap();
+
+        assert_eq!(result.query, Some(q));
+        assert_eq!(
+            result.answer,
+            vec![
+                record(
+                    "www.example.com.",
+                    RData::CNAME(Domain::from("web.example.com."))
+                ),
+                record(
+                    "web.example.com.",
+                    RData::CNAME(Domain::from("web.example.net."))
+                ),
+                record("web.example.net.", RData::A(Ipv4Addr::new(192, 0, 2, 1))),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_chain_forward() {
+        let root = mock_ns(|res| {
+            res.header.aa = 1;
+            res.answer = vec![record(
+                "www.alias.example.",
+                RData::CNAME(Domain::from("www.fwd.example.")),
+            )];
+        })
+        .await;
+        let upstream = mock_ns(|res| {
+            assert_eq!(res.header.rd, 1);
+            res.answer = vec![record(
+                "www.fwd.example.",
+                RData::A(Ipv4Addr::new(10, 0, 0, 2)),
+            )];
+        })
+        .await;
+
+        let q = Query {
+            qname: Domain::from("www.alias.example."),
+            qtype: QType::A,
+            qclass: 1,
+        };
+        let c = Config {
+            roots: vec![root],
+            forwarders: vec![format!("fwd.example.={}", upstream).parse().unwrap()],
+            ..config()
+        };
+        let result = resolve_chain(&q, &c, &[]).await.unwrap();
+
+        assert_eq!(result.answer.len(), 2);
+        assert_eq!(
+            result.answer[1],
+            record("www.fwd.example.", RData::A(Ipv4Addr::new(10, 0, 0, 2)))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_from_rrsets() {
+        rrset::cache(
+            false,
+            &[
+                record(
+                    "www.rrsets.example.",
+                    RData::CNAME(Domain::from("web.rrsets.example.")),
+                ),
+                record("web.rrsets.example.", RData::A(Ipv4Addr::new(192, 0, 2, 1))),
+            ],
+            &[],
+            &[record(
+                "ns.rrsets.example.",
+                RData::A(Ipv4Addr::new(192, 0, 2, 53)),
+            )],
+        );
+
+        // the alias is followed through the cached RRsets
+        let mut req = request(0, 1);
+        req.query.as_mut().unwrap().qname = Domain::from("www.rrsets.example.");
+        let result = resolve(req.clone(), &config()).await.unwrap();
+        assert_eq!(result.header.id, 1234);
+        assert_eq!((result.header.ra, result.header.ad), (1, 0));
+        assert_eq!(result.answer.len(), 2);
+
+        req.query.as_mut().unwrap().qname = Domain::from("web.rrsets.example.");
+        let result = resolve(req.clone(), &config()).await.unwrap();
+        assert_eq!(
+            result.answer,
+            vec![record(
+                "web.rrsets.example.",
+                RData::A(Ipv4Addr::new(192, 0, 2, 1))
+            )]
+        );
+
+        // glue is not given to clients, so this is resolved without roots
+        req.query.as_mut().unwrap().qname = Domain::from("ns.rrsets.example.");
+        assert!(resolve(req, &config()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_forward_zone() {
+        let upstream = mock_ns(|res| {
+            res.header.aa = 1;
+            res.header.ad = 1;
+            res.answer = vec![record(
+                "www.corp.example.",
+                RData::A(Ipv4Addr::new(10, 0, 0, 1)),
+            )];
+        })
+        .await;
+
+        let c = Config {
+            forwarders: vec![format!("corp.example.={}", upstream).parse().unwrap()],
+            ..config()
+        };
+        let mut req = request(0, 1);
+        req.query.as_mut().unwrap().qname = Domain::from("www.corp.example.");
+
+        req.header.cd = 1;
+
+        // the flags of the upstream are not passed on
+        let result = resolve(req, &c).await.unwrap();
+        assert_eq!(result.header.id, 1234);
+        assert_eq!(
+            (
+                result.header.qr,
+                result.header.aa,
+                result.header.rd,
+                result.header.ra,
+                result.header.ad,
+                result.header.cd
+            ),
+            (1, 0, 1, 1, 0, 1)
+        );
+        assert_eq!(result.answer.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_forward_zone_bailiwick() {
+        let upstream = mock_ns(|res| {
+            res.answer = vec![
+                record("www.fwd-bw.example.", RData::A(Ipv4Addr::new(10, 0, 0, 3))),
+                record("www.bank.example.", RData::A(Ipv4Addr::new(10, 6, 6, 6))),
+            ];
+            res.authority = vec![record(
+                "bank.example.",
+                RData::NS(Domain::from("ns.bank.example.")),
+            )];
+            res.additional = vec![record(
+                "ns.bank.example.",
+                RData::A(Ipv4Addr::new(10, 6, 6, 53)),
+            )];
+        })
+        .await;
+
+        let c = Config {
+            forwarders: vec![format!("fwd-bw.example.={}", upstream).parse().unwrap()],
+            ..config()
+        };
+        let mut req = request(0, 1);
+        req.query.as_mut().unwrap().qname = Domain::from("www.fwd-bw.example.");
+        resolve(req, &c).await.unwrap();
+
+        let name = Domain::from("www.bank.example.");
+        assert!(rrset::resolve(&Domain::from("www.fwd-bw.example."), QType::A, 1).is_some());
+        assert_eq!(rrset::resolve(&name, QType::A, 1), None);
+        assert_eq!(rrset::delegation(&name), None);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_stale() {
+        cache::set_stale_window(3600);
+        cache::cache(
+            "www.stale.example.".to_owned(),
+            QType::A,
+            RCode::NoError,
+            &[Resource {
+                ttl: 0,
+                ..record("www.stale.example.", RData::A(Ipv4Addr::new(192, 0, 2, 1)))
+            }],
+            &[],
+            &[],
+        );
+
+        // an upstream which never answers
+        let down = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut req = request(0, 1);
+        req.query.as_mut().unwrap().qname = Domain::from("www.stale.example.");
+
+        // the upstream times out
+        let failing = Config {
+            forwarders: vec![format!("stale.example.={}/100", down.local_addr().unwrap())
+                .parse()
+                .unwrap()],
+            stale_window: Duration::from_secs(3600),
+            ..config()
+        };
+        let result = resolve(req.clone(), &failing).await.unwrap();
+        assert_eq!(result.header.id, 1234);
+        assert_eq!(result.answer[0].ttl, cache::STALE_TTL);
+        // nothing is validated, so AD is never set
+        assert_eq!((result.header.ra, result.header.ad), (1, 0));
+
+        // resolving takes longer than the client is willing to wait
+        let slow = Config {
+            forwarders: vec![format!("stale.example.={}", down.local_addr().unwrap())
+                .parse()
+                .unwrap()],
+            stale_answer_timeout: Duration::from_millis(100),
+            ..failing.clone()
+        };
+        let result = time::timeout(Duration::from_secs(1), resolve(req.clone(), &slow))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(result.answer.len(), 1);
+
+        // nothing stale to serve
+        req.query.as_mut().unwrap().qname = Domain::from("none.stale.example.");
+        assert!(resolve(req, &failing).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_stale_keeps_lookup() {
+        cache::set_stale_window(3600);
+        cache::cache(
+            "www.slow.example.".to_owned(),
+            QType::A,
+            RCode::NoError,
+            &[Resource {
+                ttl: 0,
+                ..record("www.slow.example.", RData::A(Ipv4Addr::new(192, 0, 2, 1)))
+            }],
+            &[],
+            &[],
+        );
+
+        // an upstream which answers after the stale answer is served
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let c = Config {
+            forwarders: vec![format!("slow.example.={}", sock.local_addr().unwrap())
+                .parse()
+                .unwrap()],
+            stale_window: Duration::from_secs(3600),
+            stale_answer_timeout: Duration::from_millis(100),
+            ..config()
+        };
+        let mut req = request(0, 1);
+        req.query.as_mut().unwrap().qname = Domain::from("www.slow.example.");
+
+        let result = resolve(req, &c).await.unwrap();
+        assert_eq!(result.answer[0].ttl, cache::STALE_TTL);
+
+        // the query sent for the client is answered and refreshes the cache
+        let mut buf = [0; 512];
+        let (len, from) = sock.recv_from(&mut buf).await.unwrap();
+        let mut res = Message::from_bytes(&buf[..len]).unwrap();
+        res.header.qr = 1;
+        res.edns = None;
+        res.answer = vec![record(
+            "www.slow.example.",
+            RData::A(Ipv4Addr::new(192, 0, 2, 2)),
+        )];
+        sock.send_to(&res.to_vec().await.unwrap(), from)
+            .await
+            .unwrap();
+
+        time::sleep(Duration::from_millis(100)).await;
+        let cache_data = cache::resolve("www.slow.example.".to_owned(), QType::A).unwrap();
+        assert_eq!(
+            cache_data.answer[0].rdata,
+            RData::A(Ipv4Addr::new(192, 0, 2, 2))
+        );
+
+        // and it is not sent again
+        let again = time::timeout(Duration::from_millis(100), sock.recv_from(&mut buf)).await;
+        assert!(again.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_query_servers() {
+        let refused = mock_ns(|res| res.header.rcode = RCode::Refused).await;
+        let servfail = mock_ns(|res| res.header.rcode = RCode::ServFail).await;
+        let ok = mock_ns(|res| {
+            res.answer = vec![record(
+                "example.com.",
+                RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            )]
+        })
+        .await;
+
+        let q = Query {
+            qname: Domain::from("example.com."),
+            qtype: QType::A,
+            qclass: 1,
+        };
+        let result = query_servers(q, "example.com.", &[refused, servfail, ok], &config())
+            .await
+            .unwrap();
+
+        assert_eq!(result.answer.len(), 1);
+        assert!(infra::is_lame(refused.ip(), "example.com."));
+    }
+
+    #[tokio::test]
+    async fn test_query_servers_ip_preference() {
+        let v4 = mock_ns_on("127.0.0.1:0", |res| {
+            res.answer = vec![record(
+                "example.org.",
+                RData::A(Ipv4Addr::new(192, 0, 2, 4)),
+            )]
+        })
+        .await;
+        let v6 = mock_ns_on("[::1]:0", |res| {
+            res.answer = vec![record(
+                "example.org.",
+                RData::A(Ipv4Addr::new(192, 0, 2, 6)),
+            )]
+        })
+        .await;
+
+        let q = Query {
+            qname: Domain::from("example.org."),
+            qtype: QType::A,
+            qclass: 1,
+        };
+        for (ip_preference, expected) in [
+            (IpPreference::PreferIpv4, [192, 0, 2, 4]),
+            (IpPreference::PreferIpv6, [192, 0, 2, 6]),
+            (IpPreference::Ipv4Only, [192, 0, 2, 4]),
+            (IpPreference::Ipv6Only, [192, 0, 2, 6]),
+        ] {
+            let c = Config {
+                ip_preference,
+                ..config()
+            };
+            let servers = match ip_preference {
+                IpPreference::Ipv6Only => [v4, v6],
+                _ => [v6, v4],
+            };
+
+            let result = query_servers(q.clone(), "example.org.", &servers, &c)
+                .await
+                .unwrap();
+            assert_eq!(result.answer[0].rdata, RData::A(expected.into()));
+        }
+
+        let c = Config {
+            ip_preference: IpPreference::Ipv6Only,
+            ..config()
+        };
+        assert!(query_servers(q, "example.org.", &[v4], &c).await.is_err());
+    }
+
+    /// Serve TCP on localhost with the limits in `c`.
+    async fn tcp_server(c: Config) -> SocketAddr {
+        let listener = bind_tcp(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(Semaphore::new(c.max_tcp_connections));
+        tokio::spawn(serve_tcp(listener, connections, Arc::new(c)));
+
+        addr
+    }
+
+    async fn tcp_send(stream: &mut TcpStream, req: &Message) {
+        let data = req.to_vec().await.unwrap();
+        stream.write_u16(data.len() as u16).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+    }
+
+    async fn tcp_recv(stream: &mut TcpStream) -> Message {
+        let len = stream.read_u16().await.unwrap();
+        let mut buf = vec![0; len.into()];
+        stream.read_exact(&mut buf).await.unwrap();
+
+        Message::from_bytes(&buf).unwrap()
+    }
+
+    /// Whether the server closes the connection within a second.
+    async fn tcp_closed(stream: &mut TcpStream) -> bool {
+        let mut buf = [0; 1];
+        match time::timeout(Duration::from_secs(1), stream.read(&mut buf)).await {
+            Ok(Ok(len)) => len == 0,
+            Ok(Err(_)) => true,
+            Err(_) => false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tcp_framing() {
+        let addr = tcp_server(config()).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        // NOTIMP is answered without resolving anything
+        let mut first = request(1, 1);
+        first.header.id = 1;
+        let mut second = request(1, 1);
+        second.header.id = 2;
+
+        // the second query arrives in two parts
+        tcp_send(&mut stream, &first).await;
+        let data = second.to_vec().await.unwrap();
+        stream.write_u16(data.len() as u16).await.unwrap();
+        stream.write_all(&data[..5]).await.unwrap();
+        stream.flush().await.unwrap();
+        time::sleep(Duration::from_millis(10)).await;
+        stream.write_all(&data[5..]).await.unwrap();
+
+        let mut ids = vec![];
+        for _ in 0..2 {
+            let res = tcp_recv(&mut stream).await;
+            assert_eq!(res.header.rcode, RCode::NotImp);
+            ids.push(res.header.id);
+        }
+        ids.sort_unstable();
+        assert_eq!(ids, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_idle_timeout() {
+        let addr = tcp_server(Config {
+            tcp_idle_timeout: Duration::from_millis(100),
+            ..config()
+        })
+        .await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        assert!(tcp_closed(&mut stream).await);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_max_connections() {
+        let c = Config {
+            max_tcp_connections: 1,
+            ..config()
+        };
+        let addr = tcp_server(c).await;
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        tcp_send(&mut first, &request(1, 1)).await;
+        tcp_recv(&mut first).await;
+
+        // closed while the first connection is open
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        assert!(tcp_closed(&mut second).await);
+
+        tcp_send(&mut first, &request(1, 1)).await;
+        assert_eq!(tcp_recv(&mut first).await.header.id, 1234);
+    }
+
+    #[tokio::test]
+    async fn test_start_invalid_ttl() {
+        let c = Config {
+            min_ttl: 600,
+            max_ttl: 300,
+            ..config()
+        };
+        let e = start(c).await.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+
+        let c = Config {
+            min_negative_ttl: 3600,
+            max_negative_ttl: 60,
+            ..config()
+        };
+        let e = start(c).await.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn test_bind_dual_stack() {
+        let sock = bind_udp("0.0.0.0:0".parse().unwrap()).unwrap();
+        let port = sock.local_addr().unwrap().port();
+        assert!(bind_udp(SocketAddr::new("::".parse().unwrap(), port)).is_ok());
+
+        let listener = bind_tcp("0.0.0.0:0".parse().unwrap()).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(bind_tcp(SocketAddr::new("::".parse().unwrap(), port)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_iterate_qname_minimisation() {
+        // answers NXDOMAIN for the empty non-terminal example.com.
+        let ns = mock_ns(|res| {
+            let q = res.query.as_ref().unwrap();
+            if q.qname.to_string() == "www.example.com." {
+                res.answer = vec![record(
+                    "www.example.com.",
+                    RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+                )];
+            } else if q.qname.to_string() == "example.com." {
+                res.header.rcode = RCode::NXDomain;
+            }
+        })
+        .await;
+
+        let q = Query {
+            qname: Domain::from("www.example.com."),
+            qtype: QType::A,
+            qclass: 1,
+        };
+        for (qname_minimisation, rcode, answers) in [
+            (QnameMinimisation::Off, RCode::NoError, 1),
+            (QnameMinimisation::Relaxed, RCode::NoError, 1),
+            (QnameMinimisation::Strict, RCode::NXDomain, 0),
+        ] {
+            let c = Config {
+                roots: vec![ns],
+                qname_minimisation,
+                ..config()
+            };
+
+            let result = iterate(q.clone(), &c, &[]).await.unwrap();
+            assert_eq!(result.header.rcode, rcode);
+            assert_eq!(result.answer.len(), answers);
+        }
+        // the answer can be used for other queries
+        let (records, _) = rrset::resolve(&q.qname, QType::A, 1).unwrap();
+        assert_eq!(records.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_next_depth() {
+        let mut depth = 0;
+        let mut list = vec![];
+        for minimised in 0.. {
+            depth = next_depth(depth, 20, minimised);
+            list.push(depth);
+            if depth == 20 {
+                break;
+            }
+        }
+
+        assert_eq!(list, vec![1, 2, 3, 4, 6, 8, 11, 14, 17, 20]);
+        assert_eq!(next_depth(0, 0, 0), 0);
+    }
+
+    #[tokio::test]
+    async fn test_delegation() {
+        let mut res = request(0, 1);
+        res.header.qr = 1;
+        res.authority = vec![Resource {
+            name: Domain::from("example.com."),
+            _type: QType::NS,
+            class: 1,
+            ttl: 300,
+            rdata: RData::NS(Domain::from("ns.example.com.")),
+        }];
+
+        let qname = Domain::from("www.example.com.");
+        assert_eq!(
+            delegation(&res, &Domain::from("com."), &qname),
+            Some(Domain::from("example.com."))
+        );
+        // not below the current zone
+        assert_eq!(
+            delegation(&res, &Domain::from("example.com."), &qname),
+            None
+        );
+        // not for the query name
+        assert_eq!(
+            delegation(&res, &Domain::from("com."), &Domain::from("example.net.")),
+            None
+        );
+
+        res.header.aa = 1;
+        assert_eq!(delegation(&res, &Domain::from("com."), &qname), None);
+    }
+
+    #[tokio::test]
+    async fn test_prime() {
+        let ns = mock_ns(|res| {
+            res.answer = vec![Resource {
+                name: Domain::from("."),
+                _type: QType::NS,
+                class: 1,
+                ttl: 518400,
+                rdata: RData::NS(Domain::from("a.root-servers.net.")),
+            }];
+            res.additional = vec![record(
+                "a.root-servers.net.",
+                RData::A(Ipv4Addr::new(198, 41, 0, 4)),
+            )];
+        })
+        .await;
+        let c = Config {
+            roots: vec![ns],
+            ..config()
+        };
+        assert_eq!(prime(&c).await, vec!["198.41.0.4:53".parse().unwrap()]);
+
+        let ns = mock_ns(|res| res.header.rcode = RCode::Refused).await;
+        let c = Config {
+            roots: vec![ns],
+            ..config()
+        };
+        assert_eq!(prime(&c).await, vec![ns]);
+    }
+
+    #[tokio::test]
+    async fn test_bailiwick() {
+        let mut res = request(0, 1);
+        res.answer = vec![record(
+            "www.example.com.",
+            RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        )];
+        res.authority = vec![record(
+            "example.net.",
+            RData::NS(Domain::from("ns.example.net.")),
+        )];
+        res.additional = vec![
+            record("NS.EXAMPLE.com.", RData::A(Ipv4Addr::new(192, 0, 2, 53))),
+            record("bank.example.", RData::A(Ipv4Addr::new(203, 0, 113, 1))),
+        ];
+
+        let res = bailiwick(res, &Domain::from("example.com."));
+        assert_eq!(res.answer.len(), 1);
+        assert_eq!(res.authority, vec![]);
+        assert_eq!(
+            res.additional,
+            vec![record(
+                "NS.EXAMPLE.com.",
+                RData::A(Ipv4Addr::new(192, 0, 2, 53))
+            )]
+        );
+        assert_eq!(res.header.ar_count, 1);
+
+        // the root is trusted for every name
+        let mut res = request(0, 1);
+        res.additional = vec![record(
+            "bank.example.",
+            RData::A(Ipv4Addr::new(203, 0, 113, 1)),
+        )];
+        assert_eq!(bailiwick(res, &Domain::from(".")).additional.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_referral() {
+        let mut res = request(0, 1);
+        res.authority = vec![
+            Resource {
+                name: Domain::from("example.com."),
+                _type: QType::NS,
+                class: 1,
+                ttl: 300,
+                rdata: RData::NS(Domain::from("ns1.example.net.")),
+            },
+            Resource {
+                name: Domain::from("example.com."),
+                _type: QType::NS,
+                class: 1,
+                ttl: 300,
+                rdata: RData::NS(Domain::from("ns.example.com.")),
+            },
+        ];
+        res.additional = vec![
+            record("NS.example.com.", RData::A(Ipv4Addr::new(192, 0, 2, 53))),
+            Resource {
+                name: Domain::from("ns.example.com."),
+                _type: QType::AAAA,
+                class: 1,
+                ttl: 300,
+                rdata: RData::AAAA("2001:db8::53".parse().unwrap()),
+            },
+            record("other.example.com.", RData::A(Ipv4Addr::new(192, 0, 2, 1))),
+        ];
+
+        let (names, glue) = referral(&res);
+        assert_eq!(
+            names,
+            vec![
+                Domain::from("ns1.example.net."),
+                Domain::from("ns.example.com.")
+            ]
+        );
+        assert_eq!(
+            glue,
+            vec![
+                "192.0.2.53:53".parse().unwrap(),
+                "[2001:db8::53]:53".parse().unwrap()
+            ]
+        );
+
+        res.additional.clear();
+        let (names, glue) = referral(&res);
+        assert_eq!(names.len(), 2);
+        assert_eq!(glue, vec![]);
+    }
+
+    #[tokio::test]
+    async fn test_follow_chain_dname() {
+        let records = vec![
+            record("example.com.", RData::DNAME(Domain::from("example.net."))),
+            record("www.example.net.", RData::A(Ipv4Addr::new(192, 0, 2, 1))),
+        ];
+
+        let mut answer = vec![];
+        let mut chain = vec![];
+        let next = follow_chain(
+            &Domain::from("www.example.com."),
+            QType::A,
+            &records,
+            &mut answer,
+            &mut chain,
+        )
+        .unwrap();
+
+        assert_eq!(next, None);
+        assert_eq!(
+            answer,
+            vec![
+                record("example.com.", RData::DNAME(Domain::from("example.net."))),
+                record(
+                    "www.example.com.",
+                    RData::CNAME(Domain::from("www.example.net."))
+                ),
+                record("www.example.net.", RData::A(Ipv4Addr::new(192, 0, 2, 1))),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_follow_chain_loop() {
+        let records = vec![
+            record(
+                "a.example.com.",
+                RData::CNAME(Domain::from("b.example.com.")),
+            ),
+            record(
+                "b.example.com.",
+                RData::CNAME(Domain::from("a.example.com.")),
+            ),
+        ];
+
+        let qname = Domain::from("a.example.com.");
+        let result = follow_chain(
+            &qname,
+            QType::A,
+            &records,
+            &mut vec![],
+            &mut vec![qname.clone()],
+        );
+        assert!(result.is_err());
     }
 }
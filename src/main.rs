@@ -1,19 +1,91 @@
-use pretty_dns_server::server;
-use std::{io, net::Ipv4Addr};
+use pretty_dns_server::{forward, hints, server};
+use std::{io, net::IpAddr, path::PathBuf, time::Duration};
 use structopt::StructOpt;
-use tracing;
-use tracing_subscriber;
 
 #[derive(StructOpt, Debug)]
 struct Config {
     #[structopt(short, long)]
     debug: bool,
 
-    #[structopt(long, default_value = "0.0.0.0")]
-    addr: Ipv4Addr,
+    /// address to listen on, can be given more than once to listen on both ipv4 and ipv6
+    #[structopt(long, default_value = "0.0.0.0", number_of_values = 1)]
+    addr: Vec<IpAddr>,
 
     #[structopt(short, long, default_value = "53")]
     port: u16,
+
+    #[structopt(long, default_value = "1232")]
+    udp_payload_size: u16,
+
+    /// seconds to keep an idle tcp connection open
+    #[structopt(long, default_value = "10")]
+    tcp_idle_timeout: u64,
+
+    #[structopt(long, default_value = "100")]
+    max_tcp_connections: usize,
+
+    /// root hints file in the format of named.root, the built-in hints are used by default
+    #[structopt(long, parse(from_os_str))]
+    root_hints: Option<PathBuf>,
+
+    /// address family of the nameservers: any, prefer-ipv4, prefer-ipv6, ipv4-only or ipv6-only
+    #[structopt(long, default_value = "any")]
+    ip_preference: server::IpPreference,
+
+    /// qname minimisation mode: off, relaxed or strict
+    #[structopt(long, default_value = "relaxed")]
+    qname_minimisation: server::QnameMinimisation,
+
+    /// forward queries to this server instead of resolving them, as ADDRESS[:PORT][/TIMEOUT_MS],
+    /// can be given more than once
+    #[structopt(long, number_of_values = 1)]
+    forward: Vec<forward::Upstream>,
+
+    /// forward queries for names in a zone, as ZONE=UPSTREAM[,UPSTREAM...], can be given more
+    /// than once
+    #[structopt(long, number_of_values = 1)]
+    forward_zone: Vec<forward::Rule>,
+
+    /// file with forwarding rules, each line is ZONE UPSTREAM... or an UPSTREAM for every query
+    #[structopt(long, parse(from_os_str))]
+    forward_file: Option<PathBuf>,
+
+    /// the most records kept in the cache
+    #[structopt(long, default_value = "10000")]
+    cache_size: usize,
+
+    /// seconds to keep expired records to answer with when they cannot be resolved, 0 disables it
+    #[structopt(long, default_value = "86400")]
+    stale_window: u64,
+
+    /// milliseconds to wait for a resolution before answering with stale records
+    #[structopt(long, default_value = "1800")]
+    stale_answer_timeout: u64,
+
+    /// resolve records answered from the cache this many times again before they expire, 0
+    /// disables it
+    #[structopt(long, default_value = "10")]
+    prefetch_hits: u64,
+
+    /// fraction of the ttl left when a record is resolved again
+    #[structopt(long, default_value = "0.1")]
+    prefetch_fraction: f64,
+
+    /// the smallest ttl in seconds that records are cached for
+    #[structopt(long, default_value = "0")]
+    min_ttl: u32,
+
+    /// the largest ttl in seconds that records are cached for
+    #[structopt(long, default_value = "86400")]
+    max_ttl: u32,
+
+    /// the smallest ttl in seconds that nxdomain and nodata responses are cached for
+    #[structopt(long, default_value = "0")]
+    min_negative_ttl: u32,
+
+    /// the largest ttl in seconds that nxdomain and nodata responses are cached for
+    #[structopt(long, default_value = "10800")]
+    max_negative_ttl: u32,
 }
 
 #[tokio::main]
@@ -27,9 +99,42 @@ async fn main() -> io::Result<()> {
         tracing_subscriber::fmt().init();
     }
 
+    let roots = match c.root_hints {
+        Some(path) => hints::load(&path)?,
+        None => hints::default(),
+    };
+
+    let mut forwarders = c.forward_zone;
+    if !c.forward.is_empty() {
+        forwarders.push(forward::Rule {
+            zone: ".".into(),
+            upstreams: c.forward,
+        });
+    }
+    if let Some(path) = c.forward_file {
+        forwarders.extend(forward::load(&path)?);
+    }
+    let forwarders = forward::merge(forwarders);
+
     server::start(server::Config {
-        addr: c.addr,
+        addrs: c.addr,
         port: c.port,
+        udp_payload_size: c.udp_payload_size,
+        tcp_idle_timeout: Duration::from_secs(c.tcp_idle_timeout),
+        max_tcp_connections: c.max_tcp_connections,
+        roots,
+        ip_preference: c.ip_preference,
+        qname_minimisation: c.qname_minimisation,
+        forwarders,
+        cache_size: c.cache_size,
+        stale_window: Duration::from_secs(c.stale_window),
+        stale_answer_timeout: Duration::from_millis(c.stale_answer_timeout),
+        prefetch_hits: c.prefetch_hits,
+        prefetch_fraction: c.prefetch_fraction,
+        min_ttl: c.min_ttl,
+        max_ttl: c.max_ttl,
+        min_negative_ttl: c.min_negative_ttl,
+        max_negative_ttl: c.max_negative_ttl,
     })
     .await
 }